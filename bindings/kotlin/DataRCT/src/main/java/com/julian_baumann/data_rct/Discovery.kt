@@ -17,8 +17,20 @@ class Discovery(context: Context, delegate: DiscoveryDelegate) {
         return internal.getDevices()
     }
 
-    fun startScanning() {
-        internal.start()
+    fun getDiscoveredDevices(): List<DiscoveredDevice> {
+        return internal.getDiscoveredDevices()
+    }
+
+    fun getDeviceRegistry(): DeviceRegistry {
+        return internal.getDeviceRegistry()
+    }
+
+    fun setDeviceTtl(seconds: ULong) {
+        internal.setDeviceTtl(seconds)
+    }
+
+    fun startScanning(methods: List<DiscoveryMethod> = listOf(DiscoveryMethod.BLE, DiscoveryMethod.MDNS)) {
+        internal.start(methods)
     }
 
     fun stopScanning() {
@@ -14,7 +14,7 @@ import kotlinx.coroutines.Dispatchers
 import kotlinx.coroutines.launch
 
 class NearbyServer(context: Context, myDevice: Device, delegate: NearbyConnectionDelegate) {
-    private val internal: InternalNearbyServer = InternalNearbyServer(myDevice, Environment.getExternalStoragePublicDirectory(Environment.DIRECTORY_DOWNLOADS).absolutePath, delegate)
+    private val internal: InternalNearbyServer = InternalNearbyServer(myDevice, Environment.getExternalStoragePublicDirectory(Environment.DIRECTORY_DOWNLOADS).absolutePath, context.filesDir.absolutePath, delegate)
     private val internalBleImplementation = BLEPeripheralManager(context, internal)
     private val internalL2CapClient = L2CAPClientManager(internal)
     private var currentIPAddress: String? = null
@@ -91,6 +91,62 @@ class NearbyServer(context: Context, myDevice: Device, delegate: NearbyConnectio
         internal.sendFile(receiver, fileUrl, progressDelegate)
     }
 
+    suspend fun sendFiles(receiver: Device, fileUrls: List<String>, progressDelegate: SendProgressDelegate?) {
+        internal.sendFiles(receiver, fileUrls, progressDelegate)
+    }
+
+    suspend fun sendDirectory(receiver: Device, directoryUrl: String, progressDelegate: SendProgressDelegate?) {
+        internal.sendDirectory(receiver, directoryUrl, progressDelegate)
+    }
+
+    suspend fun sendClipboard(receiver: Device, items: List<ClipboardItem>, progressDelegate: SendProgressDelegate?) {
+        internal.sendClipboard(receiver, items, progressDelegate)
+    }
+
+    fun cancelSend(sendId: String) {
+        internal.cancelSend(sendId)
+    }
+
+    fun confirmVerificationCode(sendId: String, confirmed: Boolean) {
+        internal.confirmVerificationCode(sendId, confirmed)
+    }
+
+    fun getTrustedDevices(): List<TrustedDevice> {
+        return internal.getTrustedDevices()
+    }
+
+    fun pairDevice(device: Device, identityPublicKey: ByteArray) {
+        internal.pairDevice(device, identityPublicKey)
+    }
+
+    fun forgetDevice(deviceId: String) {
+        internal.forgetDevice(deviceId)
+    }
+
+    fun getPendingRequests(): List<ConnectionRequest> {
+        return internal.getPendingRequests()
+    }
+
+    fun declinePendingRequests() {
+        internal.declinePendingRequests()
+    }
+
+    fun setRequestTimeout(seconds: ULong) {
+        internal.setRequestTimeout(seconds)
+    }
+
+    fun setMaxTransferSize(bytes: ULong) {
+        internal.setMaxTransferSize(bytes)
+    }
+
+    fun setAutoAcceptRules(rules: List<PolicyRule>) {
+        internal.setAutoAcceptRules(rules)
+    }
+
+    fun setDeviceRegistry(deviceRegistry: DeviceRegistry) {
+        internal.setDeviceRegistry(deviceRegistry)
+    }
+
     suspend fun stop() {
         internal.stop()
     }
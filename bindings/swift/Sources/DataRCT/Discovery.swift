@@ -21,10 +21,22 @@ public class Discovery {
         internalHandler.addBleImplementation(implementation: bleImplementation)
     }
     
-    public func startScan() throws {
+    public func getDiscoveredDevices() -> [DiscoveredDevice] {
+        return internalHandler.getDiscoveredDevices()
+    }
+    
+    public func getDeviceRegistry() -> DeviceRegistry {
+        return internalHandler.getDeviceRegistry()
+    }
+    
+    public func setDeviceTtl(seconds: UInt64) {
+        internalHandler.setDeviceTtl(seconds: seconds)
+    }
+    
+    public func startScan(methods: [DiscoveryMethod] = [.ble, .mdns]) throws {
         try bleImplementation.ensureValidState()
         
-        internalHandler.start()
+        internalHandler.start(methods: methods)
     }
     
     public func stopScan() throws {
@@ -65,7 +65,7 @@ public class NearbyServer {
     public var state: BluetoothState { get { bleServer.state } }
 
     public init(myDevice: Device, storage: String, delegate: NearbyServerDelegate) {
-        internalHandler = InternalNearbyServer(myDevice: myDevice, fileStorage: storage, delegate: delegate)
+        internalHandler = InternalNearbyServer(myDevice: myDevice, fileStorage: storage, configStorage: NearbyServer.getConfigStorage(), delegate: delegate)
         bleServer = BLEPeripheralManager(handler: internalHandler, delegate: delegate)
 
         internalHandler.addBleImplementation(bleImplementation: bleServer)
@@ -96,6 +96,12 @@ public class NearbyServer {
         monitor.start(queue: queue)
     }
 
+    private static func getConfigStorage() -> String {
+        let applicationSupport = FileManager.default.urls(for: .applicationSupportDirectory, in: .userDomainMask)[0]
+
+        return applicationSupport.appendingPathComponent("DataRCT").path
+    }
+
     public func changeDevice(_ newDevice: Device) {
         internalHandler.changeDevice(newDevice: newDevice)
     }
@@ -113,6 +119,68 @@ public class NearbyServer {
         try await internalHandler.sendFile(receiver: device, filePath: url, progressDelegate: progress)
     }
 
+    @available(macOS 13.0, *)
+    @available(iOS 14.0, *)
+    public func sendFiles(to device: Device, urls: [String], progress: SendProgressDelegate?) async throws {
+        try await internalHandler.sendFiles(receiver: device, filePaths: urls, progressDelegate: progress)
+    }
+
+    @available(macOS 13.0, *)
+    @available(iOS 14.0, *)
+    public func sendDirectory(to device: Device, url: String, progress: SendProgressDelegate?) async throws {
+        try await internalHandler.sendDirectory(receiver: device, directoryPath: url, progressDelegate: progress)
+    }
+
+    @available(macOS 13.0, *)
+    @available(iOS 14.0, *)
+    public func sendClipboard(to device: Device, items: [ClipboardItem], progress: SendProgressDelegate?) async throws {
+        try await internalHandler.sendClipboard(receiver: device, items: items, progressDelegate: progress)
+    }
+
+    public func cancelSend(id: String) {
+        internalHandler.cancelSend(sendId: id)
+    }
+
+    public func confirmVerificationCode(sendId: String, confirmed: Bool) {
+        internalHandler.confirmVerificationCode(sendId: sendId, confirmed: confirmed)
+    }
+
+    public func getTrustedDevices() -> [TrustedDevice] {
+        return internalHandler.getTrustedDevices()
+    }
+
+    public func pairDevice(_ device: Device, identityPublicKey: Data) throws {
+        try internalHandler.pairDevice(device: device, identityPublicKey: identityPublicKey)
+    }
+
+    public func forgetDevice(id: String) throws {
+        try internalHandler.forgetDevice(deviceId: id)
+    }
+
+    public func getPendingRequests() -> [ConnectionRequest] {
+        return internalHandler.getPendingRequests()
+    }
+
+    public func declinePendingRequests() {
+        internalHandler.declinePendingRequests()
+    }
+
+    public func setRequestTimeout(seconds: UInt64) {
+        internalHandler.setRequestTimeout(seconds: seconds)
+    }
+
+    public func setMaxTransferSize(bytes: UInt64) {
+        internalHandler.setMaxTransferSize(bytes: bytes)
+    }
+
+    public func setAutoAcceptRules(_ rules: [PolicyRule]) {
+        internalHandler.setAutoAcceptRules(rules: rules)
+    }
+
+    public func setDeviceRegistry(_ deviceRegistry: DeviceRegistry) {
+        internalHandler.setDeviceRegistry(deviceRegistry: deviceRegistry)
+    }
+
     public func stop() throws {
         try bleServer.ensureValidState()
 
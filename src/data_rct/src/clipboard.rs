@@ -0,0 +1,68 @@
+use protocol::communication::{ClipboardRepresentation, ClipboardTransferIntent};
+
+pub const MIME_TYPE_TEXT: &str = "text/plain";
+pub const MIME_TYPE_HTML: &str = "text/html";
+pub const MIME_TYPE_PNG: &str = "image/png";
+pub const MIME_TYPE_URI_LIST: &str = "text/uri-list";
+
+/// Upper limit for all representations together, as the receiver keeps the clipboard in memory.
+pub const MAX_CLIPBOARD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// One representation of the clipboard content, e.g. the HTML version of a copied text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClipboardItem {
+    pub mime_type: String,
+    pub data: Vec<u8>
+}
+
+impl ClipboardItem {
+    pub fn text(text: &str) -> Self {
+        return Self {
+            mime_type: MIME_TYPE_TEXT.to_string(),
+            data: text.as_bytes().to_vec()
+        };
+    }
+
+    pub fn representation(&self) -> ClipboardRepresentation {
+        return ClipboardRepresentation {
+            mime_type: self.mime_type.clone(),
+            size: self.data.len() as u64
+        };
+    }
+}
+
+/// Accepts `type/subtype` with optional parameters, e.g. `text/plain; charset=utf-8`.
+pub fn is_valid_mime_type(mime_type: &str) -> bool {
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+
+    let Some((media_type, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+
+    let is_token = |token: &str| !token.is_empty()
+        && token.chars().all(|character| character.is_ascii_alphanumeric() || "!#$&-^_.+".contains(character));
+
+    return is_token(media_type) && is_token(subtype);
+}
+
+pub fn clipboard_size(clipboard_transfer_intent: &ClipboardTransferIntent) -> u64 {
+    return clipboard_transfer_intent.representations.iter()
+        .fold(0u64, |size, representation| size.saturating_add(representation.size));
+}
+
+/// Checks the representations of a clipboard transfer, so nothing unexpected is read into memory.
+pub fn validate_clipboard(clipboard_transfer_intent: &ClipboardTransferIntent) -> Result<(), String> {
+    if clipboard_transfer_intent.representations.is_empty() {
+        return Err("The clipboard is empty".to_string());
+    }
+
+    if let Some(representation) = clipboard_transfer_intent.representations.iter().find(|representation| !is_valid_mime_type(&representation.mime_type)) {
+        return Err(format!("Invalid MIME type: {}", representation.mime_type));
+    }
+
+    if clipboard_size(clipboard_transfer_intent) > MAX_CLIPBOARD_SIZE {
+        return Err(format!("The clipboard is larger than {} bytes", MAX_CLIPBOARD_SIZE));
+    }
+
+    return Ok(());
+}
@@ -1,45 +1,453 @@
 use std::error::Error;
 use std::io::{Read, Write};
+use hkdf::Hkdf;
 use prost_stream::Stream;
-use rand_core::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey};
-use protocol::communication::{EncryptionRequest, EncryptionResponse};
-use crate::encryption::generate_iv;
-use crate::encryption::EncryptedStream;
-
-pub async fn initiate_sender_communication<T>(mut stream: T) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
-    let secret = EphemeralSecret::random_from_rng(OsRng);
+use protocol::prost::Message;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret};
+use protocol::communication::{EncryptionMode, EncryptionRequest, EncryptionResponse, HandshakeMode, HandshakeNegotiation, HandshakeNegotiationResponse, MessageHeader, NoiseMessage, VerificationCommitment, VerificationNonce};
+use protocol::communication::message_header::MessageTypes;
+use snow::Builder;
+use crate::encryption::{EncryptedStream, HandshakeDetails, SessionKeys, NONCE_PREFIX_SIZE};
+use crate::errors::IncomingErrors;
+use crate::identity::DeviceIdentity;
+use crate::negotiation::{local_message_header, negotiate_protocol, NegotiatedProtocol};
+
+const TRANSCRIPT_LABEL: &[u8] = b"DataRCT handshake v1";
+
+const SENDER_KEY_LABEL: &[u8] = b"DataRCT sender key";
+const SENDER_IV_LABEL: &[u8] = b"DataRCT sender iv";
+const RECEIVER_KEY_LABEL: &[u8] = b"DataRCT receiver key";
+const RECEIVER_IV_LABEL: &[u8] = b"DataRCT receiver iv";
+const VERIFICATION_CODE_LABEL: &[u8] = b"DataRCT verification code";
+const VERIFICATION_COMMITMENT_LABEL: &[u8] = b"DataRCT verification commitment";
+
+const VERIFICATION_CODE_DIGITS: u32 = 6;
+const VERIFICATION_NONCE_SIZE: usize = 32;
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const NOISE_MAX_MESSAGE_SIZE: usize = 65535;
+
+/// Supported handshake modes, in order of preference.
+const SUPPORTED_HANDSHAKE_MODES: [HandshakeMode; 2] = [HandshakeMode::NoiseXx, HandshakeMode::DataRct3dh];
+
+#[derive(Clone, Copy, PartialEq)]
+enum HandshakeRole {
+    Sender,
+    Receiver
+}
+
+/// Running hash over every handshake message, in the order they were exchanged.
+struct Transcript {
+    hasher: Sha256
+}
+
+impl Transcript {
+    fn new() -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(TRANSCRIPT_LABEL);
+
+        return Self {
+            hasher
+        };
+    }
+
+    fn append(&mut self, message: &impl Message) {
+        let encoded = message.encode_to_vec();
+
+        self.hasher.update((encoded.len() as u64).to_be_bytes());
+        self.hasher.update(encoded);
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        return self.hasher.clone().finalize().into();
+    }
+}
+
+/// Derives independent keys for both directions from the handshake output, salted with the transcript hash.
+fn derive_session_keys(shared_secret: &[u8], transcript_hash: &[u8; 32], role: HandshakeRole) -> SessionKeys {
+    let key_schedule = Hkdf::<Sha256>::new(Some(transcript_hash), shared_secret);
+
+    let mut sender_key = [0u8; 32];
+    let mut sender_iv = [0u8; NONCE_PREFIX_SIZE];
+    let mut receiver_key = [0u8; 32];
+    let mut receiver_iv = [0u8; NONCE_PREFIX_SIZE];
+
+    key_schedule.expand(SENDER_KEY_LABEL, &mut sender_key).expect("Invalid HKDF output length");
+    key_schedule.expand(SENDER_IV_LABEL, &mut sender_iv).expect("Invalid HKDF output length");
+    key_schedule.expand(RECEIVER_KEY_LABEL, &mut receiver_key).expect("Invalid HKDF output length");
+    key_schedule.expand(RECEIVER_IV_LABEL, &mut receiver_iv).expect("Invalid HKDF output length");
+
+    if role == HandshakeRole::Sender {
+        return SessionKeys {
+            send_key: sender_key,
+            send_iv: sender_iv,
+            receive_key: receiver_key,
+            receive_iv: receiver_iv
+        };
+    }
+
+    return SessionKeys {
+        send_key: receiver_key,
+        send_iv: receiver_iv,
+        receive_key: sender_key,
+        receive_iv: sender_iv
+    };
+}
+
+/// Derives the short authentication string, which the users compare on both screens.
+/// A man in the middle ends up with two different transcripts and therefore two different codes.
+/// The nonces of `exchange_verification_nonces` keep it from choosing its keys until both codes match.
+pub fn derive_verification_code(transcript_hash: &[u8; 32], sender_nonce: &[u8], receiver_nonce: &[u8]) -> String {
+    let input_key_material = [transcript_hash.as_slice(), sender_nonce, receiver_nonce].concat();
+    let key_schedule = Hkdf::<Sha256>::new(None, &input_key_material);
+
+    let mut code = [0u8; 4];
+    key_schedule.expand(VERIFICATION_CODE_LABEL, &mut code).expect("Invalid HKDF output length");
+
+    let code = u32::from_be_bytes(code) % 10u32.pow(VERIFICATION_CODE_DIGITS);
+
+    return format!("{:0width$}", code, width = VERIFICATION_CODE_DIGITS as usize);
+}
+
+fn verification_commitment(transcript_hash: &[u8; 32], nonce: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(VERIFICATION_COMMITMENT_LABEL);
+    hasher.update(transcript_hash);
+    hasher.update(nonce);
+
+    return hasher.finalize().to_vec();
+}
+
+/// Commit-reveal exchange of random nonces after the handshake, which returns the verification code.
+/// The sender commits to its nonce before it sees the one of the receiver, and the receiver sends its nonce
+/// before the sender reveals. Neither side can pick its nonce depending on the other one, so a man in the middle
+/// gets a single guess at matching codes, instead of trying different keys until they match.
+fn exchange_verification_nonces<T>(stream: &mut T, transcript_hash: &[u8; 32], role: HandshakeRole) -> Result<String, Box<dyn Error>> where T: Read + Write {
+    let mut nonce = [0u8; VERIFICATION_NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut prost_stream = Stream::new(stream);
+
+    if role == HandshakeRole::Sender {
+        let commitment = VerificationCommitment {
+            commitment: verification_commitment(transcript_hash, &nonce)
+        };
+
+        if let Err(error) = prost_stream.send(&commitment) {
+            return Err(Box::new(error));
+        }
+
+        let receiver_nonce = match prost_stream.recv::<VerificationNonce>() {
+            Ok(message) => message.nonce,
+            Err(error) => return Err(Box::new(error))
+        };
+
+        if let Err(error) = prost_stream.send(&VerificationNonce { nonce: nonce.to_vec() }) {
+            return Err(Box::new(error));
+        }
+
+        return Ok(derive_verification_code(transcript_hash, &nonce, &receiver_nonce));
+    }
+
+    let commitment = match prost_stream.recv::<VerificationCommitment>() {
+        Ok(message) => message.commitment,
+        Err(error) => return Err(Box::new(error))
+    };
+
+    if let Err(error) = prost_stream.send(&VerificationNonce { nonce: nonce.to_vec() }) {
+        return Err(Box::new(error));
+    }
+
+    let sender_nonce = match prost_stream.recv::<VerificationNonce>() {
+        Ok(message) => message.nonce,
+        Err(error) => return Err(Box::new(error))
+    };
+
+    if verification_commitment(transcript_hash, &sender_nonce) != commitment {
+        return Err(Box::new(IncomingErrors::InvalidVerificationCommitment));
+    }
+
+    return Ok(derive_verification_code(transcript_hash, &sender_nonce, &nonce));
+}
+
+/// Concatenates the DH outputs used as key material.
+/// Rejects low order points, which would result in an all zero (non contributory) output.
+fn combine_shared_secrets(shared_secrets: &[SharedSecret]) -> Result<Vec<u8>, IncomingErrors> {
+    let mut key_material = Vec::with_capacity(shared_secrets.len() * 32);
+
+    for shared_secret in shared_secrets {
+        if !shared_secret.was_contributory() {
+            return Err(IncomingErrors::InvalidForeignPublicKey);
+        }
+
+        key_material.extend_from_slice(shared_secret.as_bytes());
+    }
+
+    return Ok(key_material);
+}
+
+fn parse_public_key(public_key: Vec<u8>) -> Result<PublicKey, IncomingErrors> {
+    let public_key: [u8; 32] = match public_key.try_into() {
+        Ok(public_key) => public_key,
+        Err(_) => return Err(IncomingErrors::InvalidForeignPublicKey)
+    };
+
+    return Ok(PublicKey::from(public_key));
+}
+
+/// Both sides announce their protocol version and capabilities, before anything else is sent.
+fn exchange_sender_headers<T>(stream: &mut T, transcript: &mut Transcript) -> Result<NegotiatedProtocol, Box<dyn Error>> where T: Read + Write {
+    let local_header = local_message_header(MessageTypes::KeyExchange);
+    let mut prost_stream = Stream::new(stream);
+
+    if let Err(error) = prost_stream.send(&local_header) {
+        return Err(Box::new(error));
+    }
+
+    transcript.append(&local_header);
+
+    let remote_header = match prost_stream.recv::<MessageHeader>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error))
+    };
+
+    transcript.append(&remote_header);
+
+    return Ok(negotiate_protocol(&local_header, &remote_header)?);
+}
+
+/// The receiver always answers with its own header, so an incompatible sender can report a proper error.
+fn exchange_receiver_headers<T>(stream: &mut T, transcript: &mut Transcript) -> Result<NegotiatedProtocol, Box<dyn Error>> where T: Read + Write {
+    let local_header = local_message_header(MessageTypes::KeyExchangeResponse);
+    let mut prost_stream = Stream::new(stream);
+
+    let remote_header = match prost_stream.recv::<MessageHeader>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error))
+    };
+
+    transcript.append(&remote_header);
+
+    if let Err(error) = prost_stream.send(&local_header) {
+        return Err(Box::new(error));
+    }
+
+    transcript.append(&local_header);
+
+    return Ok(negotiate_protocol(&local_header, &remote_header)?);
+}
+
+/// Offers all supported handshake modes and returns the one selected by the receiver.
+/// Both messages are part of the transcript, so a downgrade by a man in the middle breaks the handshake.
+fn negotiate_sender_handshake_mode<T>(stream: &mut T, transcript: &mut Transcript, offered_modes: &[HandshakeMode]) -> Result<HandshakeMode, Box<dyn Error>> where T: Read + Write {
+    let negotiation = HandshakeNegotiation {
+        supported_modes: offered_modes.iter().map(|mode| *mode as i32).collect()
+    };
+
+    let mut prost_stream = Stream::new(stream);
+
+    if let Err(error) = prost_stream.send(&negotiation) {
+        return Err(Box::new(error));
+    }
+
+    transcript.append(&negotiation);
+
+    let response = match prost_stream.recv::<HandshakeNegotiationResponse>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error))
+    };
+
+    transcript.append(&response);
+
+    if !negotiation.supported_modes.contains(&response.mode) {
+        return Err(Box::new(IncomingErrors::UnsupportedHandshakeMode));
+    }
+
+    return Ok(response.mode());
+}
+
+fn negotiate_receiver_handshake_mode<T>(stream: &mut T, transcript: &mut Transcript) -> Result<HandshakeMode, Box<dyn Error>> where T: Read + Write {
+    let mut prost_stream = Stream::new(stream);
+
+    let negotiation = match prost_stream.recv::<HandshakeNegotiation>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error))
+    };
+
+    transcript.append(&negotiation);
+
+    let Some(mode) = SUPPORTED_HANDSHAKE_MODES.into_iter().find(|mode| negotiation.supported_modes.contains(&(*mode as i32))) else {
+        return Err(Box::new(IncomingErrors::UnsupportedHandshakeMode));
+    };
+
+    let response = HandshakeNegotiationResponse {
+        mode: mode as i32
+    };
+
+    if let Err(error) = prost_stream.send(&response) {
+        return Err(Box::new(error));
+    }
+
+    transcript.append(&response);
+
+    return Ok(mode);
+}
+
+/// Exchanges the message headers, negotiates the handshake mode and runs the selected handshake.
+/// The authenticated identity of the other side and the verification code, which has to be confirmed
+/// by the user for devices that are not paired yet, are stored in `handshake_details`.
+pub async fn initiate_sender_communication<T>(stream: T, identity: &DeviceIdentity) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
+    return initiate_sender_communication_with_modes(stream, identity, &SUPPORTED_HANDSHAKE_MODES).await;
+}
+
+/// Same as `initiate_sender_communication`, but only offers the given handshake modes.
+pub async fn initiate_sender_communication_with_modes<T>(mut stream: T, identity: &DeviceIdentity, offered_modes: &[HandshakeMode]) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
+    let mut transcript = Transcript::new();
+    let negotiated_protocol = exchange_sender_headers(&mut stream, &mut transcript)?;
+    let mode = negotiate_sender_handshake_mode(&mut stream, &mut transcript, offered_modes)?;
+
+    return match mode {
+        HandshakeMode::NoiseXx => noise_handshake(stream, identity, &transcript, HandshakeRole::Sender, negotiated_protocol),
+        HandshakeMode::DataRct3dh => sender_triple_diffie_hellman(stream, identity, transcript, negotiated_protocol)
+    };
+}
+
+pub fn initiate_receiver_communication<T>(mut stream: T, identity: &DeviceIdentity) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
+    let mut transcript = Transcript::new();
+    let negotiated_protocol = exchange_receiver_headers(&mut stream, &mut transcript)?;
+    let mode = negotiate_receiver_handshake_mode(&mut stream, &mut transcript)?;
+
+    return match mode {
+        HandshakeMode::NoiseXx => noise_handshake(stream, identity, &transcript, HandshakeRole::Receiver, negotiated_protocol),
+        HandshakeMode::DataRct3dh => receiver_triple_diffie_hellman(stream, identity, transcript, negotiated_protocol)
+    };
+}
+
+/// Noise XX handshake with the identity keys as static keys. The sender is the initiator.
+/// The negotiation transcript is used as prologue, and the split keys are fed into the regular key schedule.
+fn noise_handshake<T>(mut stream: T, identity: &DeviceIdentity, transcript: &Transcript, role: HandshakeRole, negotiated_protocol: NegotiatedProtocol) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
+    let local_private_key = identity.secret().to_bytes();
+    let prologue = transcript.hash();
+
+    let builder = Builder::new(NOISE_PARAMS.parse()?)
+        .local_private_key(&local_private_key)
+        .prologue(&prologue);
+
+    let mut handshake = if role == HandshakeRole::Sender {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+
+    let mut prost_stream = Stream::new(&mut stream);
+    let mut buffer = vec![0u8; NOISE_MAX_MESSAGE_SIZE];
+
+    while !handshake.is_handshake_finished() {
+        if handshake.is_my_turn() {
+            let length = handshake.write_message(&[], &mut buffer)?;
+
+            if let Err(error) = prost_stream.send(&NoiseMessage { payload: buffer[..length].to_vec() }) {
+                return Err(Box::new(error));
+            }
+        } else {
+            let message = match prost_stream.recv::<NoiseMessage>() {
+                Ok(message) => message,
+                Err(error) => return Err(Box::new(error))
+            };
+
+            handshake.read_message(&message.payload, &mut buffer)?;
+        }
+    }
+
+    let Some(foreign_identity_key) = handshake.get_remote_static() else {
+        return Err(Box::new(IncomingErrors::InvalidForeignPublicKey));
+    };
+
+    let foreign_identity_key = parse_public_key(foreign_identity_key.to_vec())?;
+
+    let handshake_hash: [u8; 32] = match handshake.get_handshake_hash().try_into() {
+        Ok(handshake_hash) => handshake_hash,
+        Err(_) => return Err(Box::new(IncomingErrors::EncryptionError))
+    };
+
+    let (initiator_key, responder_key) = handshake.dangerously_get_raw_split();
+    let key_material = [initiator_key, responder_key].concat();
+
+    let verification_code = exchange_verification_nonces(&mut stream, &handshake_hash, role)?;
+    let session_keys = derive_session_keys(&key_material, &handshake_hash, role);
+    let mut encrypted_stream = EncryptedStream::new(session_keys, stream);
+
+    encrypted_stream.handshake_details = Some(HandshakeDetails {
+        peer_identity_public_key: foreign_identity_key.to_bytes(),
+        transcript_hash: handshake_hash,
+        verification_code,
+        negotiated_protocol
+    });
+
+    return Ok(encrypted_stream);
+}
+
+/// Both sides exchange an ephemeral and their long-term identity key.
+/// The session keys are derived from DH(ephemeral, ephemeral), DH(sender identity, receiver ephemeral)
+/// and DH(sender ephemeral, receiver identity), so only the holders of both identity keys can
+/// read the stream.
+fn sender_triple_diffie_hellman<T>(mut stream: T, identity: &DeviceIdentity, mut transcript: Transcript, negotiated_protocol: NegotiatedProtocol) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
+    let secret = ReusableSecret::random_from_rng(OsRng);
     let public_key = PublicKey::from(&secret);
     let encryption_request = EncryptionRequest {
-        public_key: public_key.as_bytes().to_vec()
+        public_key: public_key.as_bytes().to_vec(),
+        supported_modes: vec![EncryptionMode::Xchacha20Poly1305Stream as i32],
+        identity_public_key: identity.public_key().to_vec()
     };
 
     let mut prost_stream = Stream::new(&mut stream);
-    let _ = prost_stream.send(&encryption_request);
+
+    if let Err(error) = prost_stream.send(&encryption_request) {
+        return Err(Box::new(error));
+    }
+
+    transcript.append(&encryption_request);
 
     let encryption_response: EncryptionResponse = match prost_stream.recv::<EncryptionResponse>() {
         Ok(message) => message,
         Err(error) => return Err(Box::new(error))
     };
 
-    let public_key: [u8; 32] = encryption_response.public_key.try_into().expect("Vec length is not 32");
-    let foreign_public_key = PublicKey::from(public_key);
+    transcript.append(&encryption_response);
+
+    if encryption_response.mode() != EncryptionMode::Xchacha20Poly1305Stream {
+        return Err(Box::new(IncomingErrors::UnsupportedEncryptionMode));
+    }
 
-    let shared_secret = secret.diffie_hellman(&foreign_public_key);
+    let foreign_public_key = parse_public_key(encryption_response.public_key)?;
+    let foreign_identity_key = parse_public_key(encryption_response.identity_public_key)?;
 
-    let iv: [u8; 24] = encryption_response.iv.try_into().expect("Vec length is not 24");
+    let key_material = combine_shared_secrets(&[
+        secret.diffie_hellman(&foreign_public_key),
+        identity.secret().diffie_hellman(&foreign_public_key),
+        secret.diffie_hellman(&foreign_identity_key)
+    ])?;
 
-    let encrypted_stream = EncryptedStream::new(shared_secret.to_bytes(), iv, stream);
+    let verification_code = exchange_verification_nonces(&mut stream, &transcript.hash(), HandshakeRole::Sender)?;
+    let session_keys = derive_session_keys(&key_material, &transcript.hash(), HandshakeRole::Sender);
+    let mut encrypted_stream = EncryptedStream::new(session_keys, stream);
+
+    encrypted_stream.handshake_details = Some(HandshakeDetails {
+        peer_identity_public_key: foreign_identity_key.to_bytes(),
+        transcript_hash: transcript.hash(),
+        verification_code,
+        negotiated_protocol
+    });
 
     return Ok(encrypted_stream);
 }
 
-pub fn initiate_receiver_communication<T>(mut stream: T) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
-    let secret = EphemeralSecret::random_from_rng(OsRng);
+fn receiver_triple_diffie_hellman<T>(mut stream: T, identity: &DeviceIdentity, mut transcript: Transcript, negotiated_protocol: NegotiatedProtocol) -> Result<EncryptedStream<T>, Box<dyn Error>> where T: Read + Write {
+    let secret = ReusableSecret::random_from_rng(OsRng);
     let public_key = PublicKey::from(&secret);
 
-    let iv = generate_iv();
-
     let mut prost_stream = Stream::new(&mut stream);
 
     let encryption_request = match prost_stream.recv::<EncryptionRequest>() {
@@ -47,19 +455,43 @@ pub fn initiate_receiver_communication<T>(mut stream: T) -> Result<EncryptedStre
         Err(error) => return Err(Box::new(error))
     };
 
-    let _ = prost_stream.send(
-        &EncryptionResponse {
-            public_key: public_key.as_bytes().to_vec(),
-            iv: iv.to_vec()
-        }
-    );
+    transcript.append(&encryption_request);
+
+    if !encryption_request.supported_modes.contains(&(EncryptionMode::Xchacha20Poly1305Stream as i32)) {
+        return Err(Box::new(IncomingErrors::UnsupportedEncryptionMode));
+    }
+
+    let encryption_response = EncryptionResponse {
+        public_key: public_key.as_bytes().to_vec(),
+        mode: EncryptionMode::Xchacha20Poly1305Stream as i32,
+        identity_public_key: identity.public_key().to_vec()
+    };
+
+    if let Err(error) = prost_stream.send(&encryption_response) {
+        return Err(Box::new(error));
+    }
+
+    transcript.append(&encryption_response);
+
+    let foreign_public_key = parse_public_key(encryption_request.public_key)?;
+    let foreign_identity_key = parse_public_key(encryption_request.identity_public_key)?;
 
-    let public_key: [u8; 32] = encryption_request.public_key.try_into().expect("Vec length is not 32");
-    let foreign_public_key = PublicKey::from(public_key);
+    let key_material = combine_shared_secrets(&[
+        secret.diffie_hellman(&foreign_public_key),
+        secret.diffie_hellman(&foreign_identity_key),
+        identity.secret().diffie_hellman(&foreign_public_key)
+    ])?;
 
-    let shared_secret = secret.diffie_hellman(&foreign_public_key);
+    let verification_code = exchange_verification_nonces(&mut stream, &transcript.hash(), HandshakeRole::Receiver)?;
+    let session_keys = derive_session_keys(&key_material, &transcript.hash(), HandshakeRole::Receiver);
+    let mut encrypted_stream = EncryptedStream::new(session_keys, stream);
 
-    let encrypted_stream = EncryptedStream::new(shared_secret.to_bytes(), iv, stream);
+    encrypted_stream.handshake_details = Some(HandshakeDetails {
+        peer_identity_public_key: foreign_identity_key.to_bytes(),
+        transcript_hash: transcript.hash(),
+        verification_code,
+        negotiated_protocol
+    });
 
     return Ok(encrypted_stream);
 }
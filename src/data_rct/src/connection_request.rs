@@ -1,23 +1,52 @@
-use std::fs::{self, File};
+use std::cmp::min;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
 use std::fmt::Debug;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use prost_stream::Stream;
 use protocol::communication::transfer_request::Intent;
-use protocol::communication::{ClipboardTransferIntent, FileTransferIntent, TransferRequest, TransferRequestResponse};
+use protocol::communication::{Capability, ClipboardTransferIntent, DeclineReason, FileManifestEntry, FileTransferIntent, SenderIntroduction, TransferAck, TransferRequest, TransferRequestResponse, TransferTrailer};
 use protocol::discovery::Device;
+use protocol::resume::ResumeState;
 use tokio::sync::RwLock;
-use crate::encryption::EncryptedReadWrite;
-use crate::nearby::ConnectionIntentType;
-use crate::BLE_BUFFER_SIZE;
+use crate::clipboard::{self, ClipboardItem};
+use crate::communication::initiate_receiver_communication;
+use crate::content::{ContentRead, ContentReader};
+use crate::device_registry::DeviceRegistry;
+use crate::encryption::{EncryptedReadWrite, HandshakeDetails};
+use crate::file_names::{self, FileCollisionStrategy, LocalPaths};
+use crate::identity::{fingerprint, DeviceIdentity};
+use crate::integrity::ContentHasher;
+use crate::nearby::{ConnectionIntentType, NearbyConnectionDelegate};
+use crate::pairing::{TrustState, TrustStore};
+use crate::policy::{AutoAcceptPolicy, PolicyDecision, RequestDetails};
+use crate::resume;
+use crate::stream::Close;
+use crate::{fraction, BLE_BUFFER_SIZE};
+
+/// Time after which a connection request, that was neither accepted nor declined, is declined.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Used for requests of older clients, which do not always include a file name.
+const DEFAULT_FILE_NAME: &str = "Received file";
 
 pub enum ReceiveProgressState {
     Unknown,
     Handshake,
-    Receiving { progress: f64 },
+    /// `progress` covers the whole transfer, `file_progress` the file at `file_index` of the manifest.
+    /// For clipboard transfers, `file_index` is the index of the representation.
+    Receiving { progress: f64, file_index: u32, file_progress: f64 },
+    /// All representations of an accepted clipboard transfer, in the order of the request.
+    ClipboardReceived { items: Vec<ClipboardItem> },
     Cancelled,
-    Finished
+    Finished,
+    /// Nobody answered the request within the request timeout, so it was declined.
+    Expired
 }
 pub trait ReceiveProgressDelegate: Send + Sync + Debug {
     fn progress_changed(&self, progress: ReceiveProgressState);
@@ -25,25 +54,289 @@ pub trait ReceiveProgressDelegate: Send + Sync + Debug {
 
 struct SharedVariables {
     receive_progress_delegate: Option<Box<dyn ReceiveProgressDelegate>>,
-    should_cancel: bool
+    should_cancel: bool,
+    responded: bool,
+    auto_accepted: bool,
+    file_collision_strategy: FileCollisionStrategy
+}
+
+/// Everything needed to turn an incoming stream into a `ConnectionRequest`.
+#[derive(Clone)]
+pub(crate) struct IncomingConnectionHandler {
+    pub delegate: Arc<Mutex<Box<dyn NearbyConnectionDelegate>>>,
+    pub file_storage: String,
+    pub identity: Arc<DeviceIdentity>,
+    pub trust_store: Arc<Mutex<TrustStore>>,
+    pub pending_requests: Arc<Mutex<Vec<Arc<ConnectionRequest>>>>,
+    /// Seconds after which unanswered requests are declined. Zero disables the timeout.
+    pub request_timeout: Arc<AtomicU64>,
+    /// File and clipboard transfers larger than this are declined without asking. Zero disables the limit.
+    pub max_transfer_size: Arc<AtomicU64>,
+    pub auto_accept_policy: Arc<Mutex<AutoAcceptPolicy>>,
+    /// Devices found by discovery, whose advertised identity senders have to match.
+    pub device_registry: Arc<Mutex<Option<Arc<DeviceRegistry>>>>,
+    /// Storage ids of the file transfers that are being received, see `resume::storage_id`.
+    pub active_transfers: Arc<Mutex<HashSet<String>>>
+}
+
+impl IncomingConnectionHandler {
+    /// `connection` closes `raw_stream` if the sender does not send its request within the request timeout,
+    /// e.g. because its user never compares the verification code.
+    pub fn handle<T>(&self, raw_stream: T, connection: Box<dyn Close + Send>) where T: Read + Write + Send + Close + 'static {
+        // Set by whichever comes first, the request or the deadline
+        let request_settled = Arc::new(AtomicBool::new(false));
+        let request_timeout = Duration::from_secs(self.request_timeout.load(Ordering::Relaxed));
+
+        if !request_timeout.is_zero() {
+            let request_settled = request_settled.clone();
+
+            thread::spawn(move || {
+                thread::sleep(request_timeout);
+
+                if !request_settled.swap(true, Ordering::SeqCst) {
+                    println!("No transfer request received in time. Closing the connection.");
+                    connection.close();
+                }
+            });
+        }
+
+        let mut encrypted_stream = match initiate_receiver_communication(raw_stream, &self.identity) {
+            Ok(request) => request,
+            Err(error) => {
+                println!("Encryption error {:}", error);
+                return;
+            }
+        };
+
+        let mut prost_stream = Stream::new(&mut encrypted_stream);
+        let sender = match prost_stream.recv::<SenderIntroduction>() {
+            Ok(SenderIntroduction { device: Some(device) }) => device,
+            Ok(_) => {
+                println!("Sender did not introduce itself");
+                return;
+            },
+            Err(error) => {
+                println!("Error {:}", error);
+                return;
+            }
+        };
+
+        let shows_verification_code = self.show_verification_code(&sender, &encrypted_stream.handshake_details);
+
+        let mut prost_stream = Stream::new(&mut encrypted_stream);
+        let received_request = prost_stream.recv::<TransferRequest>();
+        let in_time = !request_settled.swap(true, Ordering::SeqCst);
+
+        let transfer_request = match received_request {
+            Ok(message) if in_time => message,
+            Ok(_) => {
+                println!("Transfer request arrived after the request timeout");
+                self.abort_verification(sender, shows_verification_code);
+                return;
+            },
+            Err(error) => {
+                println!("Error {:}", error);
+                self.abort_verification(sender, shows_verification_code);
+                return;
+            }
+        };
+
+        let is_introduced_sender = transfer_request.device.as_ref().is_some_and(|device| device.id == sender.id);
+
+        if !is_introduced_sender || !self.matches_advertised_identity(transfer_request.device.as_ref(), encrypted_stream.handshake_details.as_ref()) {
+            println!("Sender identity does not match the advertised device. Declining the request.");
+            send_decline_response(&mut encrypted_stream, DeclineReason::UntrustedSender, None);
+            self.report_decline(&transfer_request, DeclineReason::UntrustedSender);
+            return;
+        }
+
+        // Intents of newer versions are unknown to this version
+        if transfer_request.intent.is_none() {
+            println!("Unsupported transfer request. Declining the request.");
+            send_decline_response(&mut encrypted_stream, DeclineReason::UnsupportedIntent, None);
+            self.report_decline(&transfer_request, DeclineReason::UnsupportedIntent);
+            return;
+        }
+
+        if !Self::has_safe_paths(&transfer_request) {
+            println!("Transfer request contains invalid paths. Declining the request.");
+            send_decline_response(&mut encrypted_stream, DeclineReason::InvalidRequest, None);
+            self.report_decline(&transfer_request, DeclineReason::InvalidRequest);
+            return;
+        }
+
+        if let Some(Intent::Clipboard(clipboard_transfer_intent)) = &transfer_request.intent {
+            if let Err(error) = clipboard::validate_clipboard(clipboard_transfer_intent) {
+                println!("{}. Declining the request.", error);
+                send_decline_response(&mut encrypted_stream, DeclineReason::InvalidRequest, Some(error.clone()));
+                self.report_decline(&transfer_request, DeclineReason::InvalidRequest);
+                return;
+            }
+        }
+
+        let trust_state = self.check_trust(&transfer_request, &encrypted_stream.handshake_details);
+
+        let connection_request = Arc::new(ConnectionRequest::new(
+            transfer_request,
+            Box::new(encrypted_stream),
+            self.file_storage.clone(),
+            trust_state,
+            self.trust_store.clone(),
+            self.active_transfers.clone()
+        ));
+
+        if connection_request.is_already_receiving() {
+            println!("Transfer is already being received. Declining the request.");
+            connection_request.auto_decline(DeclineReason::ReceiverBusy);
+            self.report_decline(&connection_request.transfer_request, DeclineReason::ReceiverBusy);
+            return;
+        }
+
+        let max_transfer_size = self.max_transfer_size.load(Ordering::Relaxed);
+
+        if max_transfer_size > 0 && connection_request.get_transfer_size() > max_transfer_size {
+            println!("Transfer is larger than {} bytes. Declining the request.", max_transfer_size);
+            connection_request.auto_decline(DeclineReason::TransferTooLarge);
+            self.report_decline(&connection_request.transfer_request, DeclineReason::TransferTooLarge);
+            return;
+        }
+
+        let request_details = RequestDetails::from_request(&connection_request);
+        let decision = self.auto_accept_policy.lock().expect("Failed to lock auto accept policy").decide(&request_details, SystemTime::now());
+
+        match decision {
+            PolicyDecision::Accept => {
+                println!("Accepting the request because of the auto accept policy");
+                connection_request.variables.blocking_write().auto_accepted = true;
+
+                // The app still gets the request, so it can follow the progress
+                self.delegate.lock().expect("Failed to lock delegate").received_connection_request(connection_request.clone());
+                thread::spawn(move || connection_request.accept());
+                return;
+            },
+            PolicyDecision::Decline => {
+                println!("Declining the request because of the auto accept policy");
+                connection_request.auto_decline(DeclineReason::DeclinedByPolicy);
+                self.report_decline(&connection_request.transfer_request, DeclineReason::DeclinedByPolicy);
+                return;
+            },
+            PolicyDecision::Ask => {}
+        };
+
+        {
+            let mut pending_requests = self.pending_requests.lock().expect("Failed to lock pending requests");
+            pending_requests.retain(|pending_request| pending_request.is_pending());
+            pending_requests.push(connection_request.clone());
+        }
+
+        let request_timeout = Duration::from_secs(self.request_timeout.load(Ordering::Relaxed));
+
+        if !request_timeout.is_zero() {
+            let expiring_request = connection_request.clone();
+
+            thread::spawn(move || {
+                thread::sleep(request_timeout);
+                expiring_request.expire();
+            });
+        }
+
+        self.delegate.lock().expect("Failed to lock delegate").received_connection_request(connection_request);
+    }
+
+    /// Unpaired senders are verified with the code, which the receiving user sees while the user of the sender compares it.
+    /// Senders, that do not match the advertised device, are declined anyway.
+    fn show_verification_code(&self, sender: &Device, handshake_details: &Option<HandshakeDetails>) -> bool {
+        let Some(handshake_details) = handshake_details else {
+            return false;
+        };
+
+        let trust_state = self.trust_store.lock().expect("Failed to lock trust store").get_trust_state(&sender.id, &handshake_details.peer_identity_public_key);
+
+        if trust_state == TrustState::Paired || !self.matches_advertised_identity(Some(sender), Some(handshake_details)) {
+            return false;
+        }
+
+        self.delegate.lock().expect("Failed to lock delegate").received_verification_code(sender.clone(), handshake_details.verification_code.clone());
+
+        return true;
+    }
+
+    fn abort_verification(&self, sender: Device, shows_verification_code: bool) {
+        if shows_verification_code {
+            self.delegate.lock().expect("Failed to lock delegate").verification_aborted(sender);
+        }
+    }
+
+    /// Requests declined without asking the user are still shown to the app.
+    fn report_decline(&self, transfer_request: &TransferRequest, decline_reason: DeclineReason) {
+        self.delegate.lock().expect("Failed to lock delegate").declined_connection_request(transfer_request.device.clone(), decline_reason);
+    }
+
+    /// A sender that claims to be a discovered device has to prove it holds that device's identity key.
+    /// Senders, that were not discovered, have no advertised key to check against.
+    fn matches_advertised_identity(&self, device: Option<&Device>, handshake_details: Option<&HandshakeDetails>) -> bool {
+        let (Some(device), Some(handshake_details)) = (device, handshake_details) else {
+            return false;
+        };
+
+        let device_registry = self.device_registry.lock().expect("Failed to lock device registry").clone();
+
+        let Some(device_registry) = device_registry else {
+            println!("No device registry set. The identity of {} is only checked against the paired devices.", device.id);
+            return true;
+        };
+
+        return device_registry.matches_identity(&device.id, &handshake_details.peer_identity_public_key).unwrap_or(true);
+    }
+
+    /// All files and directories have to stay within `file_storage`.
+    fn has_safe_paths(transfer_request: &TransferRequest) -> bool {
+        let Some(Intent::FileTransfer(file_transfer)) = &transfer_request.intent else {
+            return true;
+        };
+
+        return file_manifest(file_transfer).iter().all(|file| is_safe_relative_path(&file.file_name))
+            && file_transfer.directories.iter().all(|directory| is_safe_relative_path(directory));
+    }
+
+    fn check_trust(&self, transfer_request: &TransferRequest, handshake_details: &Option<HandshakeDetails>) -> TrustState {
+        let (Some(device), Some(handshake_details)) = (&transfer_request.device, handshake_details) else {
+            return TrustState::Unknown;
+        };
+
+        return self.trust_store.lock().expect("Failed to lock trust store").check_device(device, &handshake_details.peer_identity_public_key);
+    }
 }
 
 pub struct ConnectionRequest {
     transfer_request: TransferRequest,
     connection: Arc<Mutex<Box<dyn EncryptedReadWrite>>>,
+    handshake_details: Option<HandshakeDetails>,
+    trust_state: TrustState,
+    trust_store: Arc<Mutex<TrustStore>>,
     file_storage: String,
+    active_transfers: Arc<Mutex<HashSet<String>>>,
     variables: Arc<RwLock<SharedVariables>>
 }
 
 impl ConnectionRequest {
-    pub fn new(transfer_request: TransferRequest, connection: Box<dyn EncryptedReadWrite>, file_storage: String) -> Self {
+    pub fn new(transfer_request: TransferRequest, connection: Box<dyn EncryptedReadWrite>, file_storage: String, trust_state: TrustState, trust_store: Arc<Mutex<TrustStore>>, active_transfers: Arc<Mutex<HashSet<String>>>) -> Self {
+        let handshake_details = connection.handshake_details();
+
         return Self {
             transfer_request,
             connection: Arc::new(Mutex::new(connection)),
+            handshake_details,
+            trust_state,
+            trust_store,
             file_storage,
+            active_transfers,
             variables: Arc::new(RwLock::new(SharedVariables {
                 receive_progress_delegate: None,
-                should_cancel: false
+                should_cancel: false,
+                responded: false,
+                auto_accepted: false,
+                file_collision_strategy: FileCollisionStrategy::default()
             }))
         }
     }
@@ -52,10 +345,60 @@ impl ConnectionRequest {
         self.variables.blocking_write().receive_progress_delegate = Some(delegate);
     }
 
+    /// Decides what happens with received files, that already exist. Has to be set before accepting the request.
+    pub fn set_file_collision_strategy(&self, strategy: FileCollisionStrategy) {
+        self.variables.blocking_write().file_collision_strategy = strategy;
+    }
+
     pub fn get_sender(&self) -> Device {
         return self.transfer_request.clone().device.expect("Device information missing");
     }
 
+    /// Identity key the sender authenticated with during the handshake.
+    pub fn get_sender_identity_public_key(&self) -> Vec<u8> {
+        return match &self.handshake_details {
+            Some(handshake_details) => handshake_details.peer_identity_public_key.to_vec(),
+            None => vec![]
+        };
+    }
+
+    pub fn get_sender_fingerprint(&self) -> String {
+        return fingerprint(&self.get_sender_identity_public_key());
+    }
+
+    /// Whether the sender is a paired device, an unknown one, or presented a different identity key than before.
+    pub fn get_trust_state(&self) -> TrustState {
+        return self.trust_state.clone();
+    }
+
+    /// Code that has to match the one shown on the sender, before the request is accepted.
+    /// `None` if the sender is already paired.
+    pub fn get_verification_code(&self) -> Option<String> {
+        if self.trust_state == TrustState::Paired {
+            return None;
+        }
+
+        return self.handshake_details.as_ref().map(|handshake_details| handshake_details.verification_code.clone());
+    }
+
+    /// Accepting a request from an unpaired device confirms its verification code, so the device is paired afterwards.
+    /// Requests accepted by the auto accept policy were never seen by the user and do not pair.
+    fn pair_sender(&self) {
+        if self.trust_state == TrustState::Paired || self.is_auto_accepted() {
+            return;
+        }
+
+        let (Some(device), Some(handshake_details)) = (&self.transfer_request.device, &self.handshake_details) else {
+            return;
+        };
+
+        let result = self.trust_store.lock().expect("Failed to lock trust store").pair(device, &handshake_details.peer_identity_public_key);
+
+        if let Err(error) = result {
+            println!("Unable to pair device: {:?}", error);
+        }
+    }
+
     pub fn get_intent(&self) -> Intent {
         return self.transfer_request.clone().intent.expect("Intent information missing");
     }
@@ -63,7 +406,7 @@ impl ConnectionRequest {
     pub fn get_intent_type(&self) -> ConnectionIntentType {
         return match self.transfer_request.clone().intent.expect("Intent information missing") {
             Intent::FileTransfer(_) => ConnectionIntentType::FileTransfer,
-            Intent::Clipboard(_) => ConnectionIntentType::FileTransfer
+            Intent::Clipboard(_) => ConnectionIntentType::Clipboard
         };
     }
 
@@ -74,6 +417,14 @@ impl ConnectionRequest {
         };
     }
 
+    /// Files of a file transfer, in the order they are received. Empty for other intents.
+    pub fn get_file_manifest(&self) -> Vec<FileManifestEntry> {
+        return match self.get_file_transfer_intent() {
+            Some(file_transfer_intent) => file_manifest(&file_transfer_intent),
+            None => vec![]
+        };
+    }
+
     pub fn get_clipboard_intent(&self) -> Option<ClipboardTransferIntent> {
         return match self.transfer_request.clone().intent.expect("Intent information missing") {
             Intent::FileTransfer(_) => None,
@@ -81,15 +432,102 @@ impl ConnectionRequest {
         };
     }
 
+    /// True if the request was accepted by the auto accept policy, so there is no need to ask the user.
+    pub fn is_auto_accepted(&self) -> bool {
+        return self.variables.blocking_read().auto_accepted;
+    }
+
+    /// True until the request is accepted, declined or expired.
+    pub fn is_pending(&self) -> bool {
+        return !self.variables.blocking_read().responded;
+    }
+
+    /// Marks the request as answered. Returns false if it has already been answered before.
+    fn take_response(&self) -> bool {
+        let mut variables = self.variables.blocking_write();
+
+        if variables.responded {
+            return false;
+        }
+
+        variables.responded = true;
+
+        return true;
+    }
+
     pub fn decline(&self) {
-        let mut connection_guard = self.connection.lock().unwrap();
-        let mut stream = Stream::new(&mut *connection_guard);
+        if !self.take_response() {
+            return;
+        }
 
-        let _ = stream.send(&TransferRequestResponse {
-            accepted: false
-        });
+        self.send_decline(DeclineReason::DeclinedByUser);
+    }
+
+    /// Declines the request, because nobody answered it within the request timeout.
+    pub(crate) fn expire(&self) {
+        if !self.take_response() {
+            return;
+        }
+
+        println!("Connection request expired");
+
+        self.send_decline(DeclineReason::RequestExpired);
+        self.update_progress(ReceiveProgressState::Expired);
+    }
+
+    /// Declines the request before the app is asked, e.g. because it violates a limit.
+    pub(crate) fn auto_decline(&self, decline_reason: DeclineReason) {
+        if self.take_response() {
+            self.send_decline(decline_reason);
+        }
+    }
+
+    /// Size of all files of a file transfer, or of all representations of a clipboard transfer.
+    pub fn get_transfer_size(&self) -> u64 {
+        return match &self.transfer_request.intent {
+            Some(Intent::FileTransfer(file_transfer)) => file_manifest(file_transfer).iter().map(|file| file.file_size).sum(),
+            Some(Intent::Clipboard(clipboard)) => clipboard::clipboard_size(clipboard),
+            None => 0
+        };
+    }
+
+    /// Number of bytes, that still have to be written to disk. Parts received during a previous attempt are already there.
+    /// Clipboard transfers are kept in memory and need no space.
+    pub fn get_required_space(&self) -> u64 {
+        let Intent::FileTransfer(file_transfer) = self.get_intent() else {
+            return 0;
+        };
+
+        return self.get_transfer_size().saturating_sub(self.get_resume_offset(&file_transfer));
+    }
 
-        connection_guard.close();
+    /// Free space of the file storage, if the platform can tell.
+    pub fn get_available_space(&self) -> Option<u64> {
+        return fs2::available_space(&self.file_storage).ok();
+    }
+
+    /// False if the file storage does not have enough free space for the transfer.
+    /// If the free space is unknown, the transfer is assumed to fit.
+    pub fn fits_on_disk(&self) -> bool {
+        return match self.get_available_space() {
+            Some(available_space) => self.get_required_space() <= available_space,
+            None => true
+        };
+    }
+
+    /// Declines the request and tells the sender why. `message` is shown to the sender as is.
+    pub fn decline_with_reason(&self, decline_reason: DeclineReason, message: Option<String>) {
+        if !self.take_response() {
+            return;
+        }
+
+        let mut connection_guard = self.connection.lock().unwrap();
+        send_decline_response(&mut **connection_guard, decline_reason, message);
+    }
+
+    fn send_decline(&self, decline_reason: DeclineReason) {
+        let mut connection_guard = self.connection.lock().unwrap();
+        send_decline_response(&mut **connection_guard, decline_reason, None);
     }
 
     fn update_progress(&self, new_state: ReceiveProgressState) {
@@ -99,68 +537,521 @@ impl ConnectionRequest {
     }
 
     pub async fn cancel(&self) {
-        println!("trying to cancel");
         self.variables.write().await.should_cancel = true;
     }
 
     pub fn accept(&self) {
+        if !self.take_response() {
+            return;
+        }
+
         self.update_progress(ReceiveProgressState::Handshake);
+
+        match self.get_intent() {
+            Intent::FileTransfer(file_transfer) => self.accept_file(file_transfer),
+            Intent::Clipboard(clipboard) => {
+                let mut connection_guard = self.connection.lock().unwrap();
+                self.send_acceptance(&mut connection_guard, 0);
+                self.handle_clipboard(connection_guard, clipboard);
+            }
+        };
+    }
+
+    fn is_already_receiving(&self) -> bool {
+        let Some(Intent::FileTransfer(file_transfer)) = &self.transfer_request.intent else {
+            return false;
+        };
+
+        let Some(storage_id) = resume::storage_id(&file_transfer.transfer_id, &self.get_sender_identity_public_key()) else {
+            return false;
+        };
+
+        return self.active_transfers.lock().expect("Failed to lock active transfers").contains(&storage_id);
+    }
+
+    /// Two transfers with the same id from the same sender would write to the same partial files, so only one of them is received.
+    fn accept_file(&self, file_transfer: FileTransferIntent) {
+        let storage_id = resume::storage_id(&file_transfer.transfer_id, &self.get_sender_identity_public_key());
+
+        if let Some(storage_id) = &storage_id {
+            if !self.active_transfers.lock().expect("Failed to lock active transfers").insert(storage_id.clone()) {
+                println!("Transfer {} is already being received. Declining the transfer.", file_transfer.transfer_id);
+                self.send_decline(DeclineReason::ReceiverBusy);
+                self.update_progress(ReceiveProgressState::Cancelled);
+                return;
+            }
+        }
+
+        self.receive_file_transfer(file_transfer);
+
+        if let Some(storage_id) = &storage_id {
+            self.active_transfers.lock().expect("Failed to lock active transfers").remove(storage_id);
+        }
+    }
+
+    fn receive_file_transfer(&self, file_transfer: FileTransferIntent) {
+        let resume_offset = self.get_resume_offset(&file_transfer);
+
+        if !self.fits_on_disk() {
+            println!("Not enough free space for the transfer. Declining the transfer.");
+            self.send_decline(DeclineReason::InsufficientStorage);
+            self.update_progress(ReceiveProgressState::Cancelled);
+            return;
+        }
+
+        let local_paths = match self.get_local_paths(&file_transfer, resume_offset) {
+            Ok(local_paths) => local_paths,
+            Err(existing_entry) => {
+                println!("{} already exists. Declining the transfer.", existing_entry);
+                self.send_decline(DeclineReason::FileAlreadyExists);
+                self.update_progress(ReceiveProgressState::Cancelled);
+                return;
+            }
+        };
+
         let mut connection_guard = self.connection.lock().unwrap();
-        let mut stream = Stream::new(&mut *connection_guard);
+        self.send_acceptance(&mut connection_guard, resume_offset);
+        self.handle_file(connection_guard, file_transfer, resume_offset, local_paths);
+    }
 
-        let _ = stream.send(&TransferRequestResponse {
-            accepted: true
+    /// The sender is only paired once the acceptance actually went out, not for requests that end up declined.
+    fn send_acceptance(&self, stream: &mut MutexGuard<Box<dyn EncryptedReadWrite>>, resume_offset: u64) {
+        let mut proto_stream = Stream::new(&mut **stream);
+
+        let result = proto_stream.send(&TransferRequestResponse {
+            accepted: true,
+            resume_offset,
+            decline_reason: DeclineReason::Unspecified as i32,
+            decline_message: None
         });
 
-        match self.get_intent() {
-            Intent::FileTransfer(file_transfer) => self.handle_file(connection_guard, file_transfer),
-            Intent::Clipboard(clipboard) => self.handle_clipboard(clipboard)
+        if result.is_ok() {
+            self.pair_sender();
+        }
+    }
+
+    /// A resumed transfer continues writing to the files of the previous attempt.
+    fn get_local_paths(&self, file_transfer: &FileTransferIntent, resume_offset: u64) -> Result<LocalPaths, String> {
+        if resume_offset > 0 {
+            let resume_state = resume::storage_id(&file_transfer.transfer_id, &self.get_sender_identity_public_key())
+                .and_then(|storage_id| resume::load_state(&self.file_storage, &storage_id));
+
+            if let Some(resume_state) = resume_state {
+                return Ok(resume::local_paths(&resume_state));
+            }
+        }
+
+        let file_names: Vec<String> = file_manifest(file_transfer).into_iter()
+            .map(|manifest_entry| manifest_entry.file_name)
+            .collect();
+
+        return file_names::resolve_local_paths(
+            &self.file_storage,
+            &file_names,
+            &file_transfer.directories,
+            self.variables.blocking_read().file_collision_strategy
+        );
+    }
+
+    /// Compares the hash sent by the sender with the received content, and tells the sender the result.
+    fn verify_integrity(&self, stream: &mut MutexGuard<Box<dyn EncryptedReadWrite>>, content_hasher: &ContentHasher) -> bool {
+        let mut proto_stream = Stream::new(&mut **stream);
+
+        let verified = match proto_stream.recv::<TransferTrailer>() {
+            Ok(trailer) => content_hasher.verify(&trailer),
+            Err(error) => {
+                println!("Failed to receive transfer trailer: {:?}", error);
+                return false;
+            }
         };
+
+        let _ = proto_stream.send(&TransferAck { verified });
+
+        return verified;
     }
 
-    fn handle_clipboard(&self, _clipboard_transfer_intent: ClipboardTransferIntent) {
-        panic!("Not implemented yet");
+    fn supports(&self, capability: Capability) -> bool {
+        return self.handshake_details.as_ref()
+            .is_some_and(|handshake_details| handshake_details.negotiated_protocol.supports(capability));
     }
 
-    fn handle_file(&self, mut stream: MutexGuard<Box<dyn EncryptedReadWrite>>, file_transfer: FileTransferIntent) {
-        let path = Path::new(&self.file_storage);
-        let path = path.join(&file_transfer.file_name.unwrap_or_else(|| "temp.zip".to_string()));
-        let path = path.into_os_string();
+    fn get_resume_offset(&self, file_transfer: &FileTransferIntent) -> u64 {
+        if !self.supports(Capability::Resumption) {
+            return 0;
+        }
 
-        let mut file = File::create(path.clone()).expect("Failed to create file");
+        return resume::resume_offset(
+            &self.file_storage,
+            &file_transfer.transfer_id,
+            &self.get_sender_identity_public_key(),
+            &file_manifest(file_transfer)
+        );
+    }
 
-        let mut buffer = [0; BLE_BUFFER_SIZE];
-        let mut all_read = 0.0;
+    /// Receives the data of all representations into memory. The size was checked before the request was shown.
+    fn handle_clipboard(&self, mut stream: MutexGuard<Box<dyn EncryptedReadWrite>>, clipboard_transfer_intent: ClipboardTransferIntent) {
+        let total_size = clipboard::clipboard_size(&clipboard_transfer_intent);
+        let mut items = Vec::with_capacity(clipboard_transfer_intent.representations.len());
+        let mut content_hasher = ContentHasher::new();
+        let mut all_read: u64 = 0;
+        let mut completed = true;
+        let mut content_reader = ContentReader::new(&mut *stream);
+
+        for (index, representation) in clipboard_transfer_intent.representations.iter().enumerate() {
+            let mut data = vec![0; representation.size as usize];
+            let mut item_read: usize = 0;
+
+            while item_read < data.len() {
+                if self.variables.blocking_read().should_cancel {
+                    let _ = content_reader.cancel();
+                    break;
+                }
+
+                let chunk_end = min(item_read + BLE_BUFFER_SIZE, data.len());
+
+                let read_size = match content_reader.read(&mut data[item_read..chunk_end]) {
+                    Ok(ContentRead::Data(read_size)) if read_size > 0 => read_size,
+                    _ => break
+                };
+
+                content_hasher.update(&data[item_read..item_read + read_size]);
+                item_read += read_size;
+                all_read += read_size as u64;
+
+                self.update_progress(ReceiveProgressState::Receiving {
+                    progress: fraction(all_read, total_size),
+                    file_index: index as u32,
+                    file_progress: fraction(item_read as u64, representation.size)
+                });
+            }
 
-        while let Ok(read_size) = stream.read(&mut buffer) {
-            if self.variables.blocking_read().should_cancel {
+            if item_read < data.len() {
+                completed = false;
                 break;
             }
 
-            if read_size <= 0 {
+            items.push(ClipboardItem {
+                mime_type: representation.mime_type.clone(),
+                data
+            });
+        }
+
+        if completed {
+            completed = content_reader.finish().is_ok();
+        }
+
+        if completed {
+            completed = self.verify_integrity(&mut stream, &content_hasher);
+        }
+
+        let _ = stream.finish();
+        stream.close();
+
+        if completed {
+            self.update_progress(ReceiveProgressState::ClipboardReceived { items });
+            self.update_progress(ReceiveProgressState::Finished);
+        } else {
+            self.update_progress(ReceiveProgressState::Cancelled);
+        }
+    }
+
+    /// Renames the received files from their partial file to the final location, once all of them are complete.
+    /// Partial files with an unexpected size are not moved, and the transfer counts as failed.
+    fn move_into_place(&self, files: &[FileManifestEntry], partial_files_id: &str, local_paths: &LocalPaths) -> bool {
+        let mut moved = true;
+
+        for (file_index, manifest_entry) in files.iter().enumerate() {
+            let partial_path = resume::partial_file_path(&self.file_storage, partial_files_id, file_index);
+            let path = Path::new(&self.file_storage).join(&local_paths.files[file_index]);
+
+            let has_expected_size = fs::metadata(&partial_path)
+                .is_ok_and(|metadata| metadata.len() == manifest_entry.file_size);
+
+            if !has_expected_size {
+                println!("{} does not have the announced size", manifest_entry.file_name);
+                moved = false;
+                continue;
+            }
+
+            if let Some(parent) = path.parent() {
+                let _ = fs::create_dir_all(parent);
+            }
+
+            if let Err(error) = fs::rename(&partial_path, &path) {
+                println!("Failed to move {} into place: {:?}", manifest_entry.file_name, error);
+                moved = false;
+            }
+        }
+
+        resume::remove_partial_files(&self.file_storage, partial_files_id, files.len());
+
+        return moved;
+    }
+
+    /// Receives the files of the manifest, starting at `resume_offset`.
+    /// The files are written to hidden partial files, and only moved to their final location once the transfer is complete.
+    /// If the connection is lost, the partial files are kept together with the number of bytes written to disk,
+    /// so the sender can resume the transfer later on.
+    fn handle_file(&self, mut stream: MutexGuard<Box<dyn EncryptedReadWrite>>, file_transfer: FileTransferIntent, resume_offset: u64, local_paths: LocalPaths) {
+        let files = file_manifest(&file_transfer);
+        let total_size: u64 = files.iter().map(|file| file.file_size).sum();
+        let resumable = self.supports(Capability::Resumption);
+
+        let created_directories = match create_directories(&self.file_storage, &local_paths.directories) {
+            Ok(created_directories) => created_directories,
+            Err(error) => {
+                println!("Failed to create directory: {:?}", error);
+
+                let _ = ContentReader::new(&mut *stream).cancel();
+                let _ = stream.finish();
+                stream.close();
+
+                self.update_progress(ReceiveProgressState::Cancelled);
+                return;
+            }
+        };
+
+        let mut resume_state = ResumeState {
+            transfer_id: file_transfer.transfer_id.clone(),
+            sender_identity_public_key: self.get_sender_identity_public_key(),
+            files: files.clone(),
+            offset: resume_offset,
+            local_file_names: local_paths.files.clone(),
+            local_directories: local_paths.directories.clone()
+        };
+
+        if resumable {
+            let _ = resume::save_state(&self.file_storage, &resume_state);
+        }
+
+        let mut buffer = [0; BLE_BUFFER_SIZE];
+        let mut all_read: u64 = resume_offset;
+        let mut file_start: u64 = 0;
+        let mut completed = true;
+        let mut sender_cancelled = false;
+        let mut write_failed = false;
+        let mut content_hasher = ContentHasher::new();
+        let mut content_reader = ContentReader::new(&mut *stream);
+        let partial_files_id = resume::partial_files_id(&file_transfer.transfer_id, &resume_state.sender_identity_public_key);
+
+        for (file_index, manifest_entry) in files.iter().enumerate() {
+            let path = resume::partial_file_path(&self.file_storage, &partial_files_id, file_index);
+            let file_end = file_start + manifest_entry.file_size;
+
+            let file_offset = resume_offset.saturating_sub(file_start).min(manifest_entry.file_size);
+
+            // The part received during a previous attempt is only hashed
+            if file_offset > 0 && hash_received_part(&mut content_hasher, &path, file_offset).is_err() {
+                completed = false;
                 break;
             }
 
-            all_read += read_size as f64;
+            if file_end <= resume_offset && path.exists() {
+                file_start = file_end;
+                continue;
+            }
+
+            let mut file = match open_file(&path, file_offset) {
+                Ok(file) => file,
+                Err(error) => {
+                    println!("Failed to create file: {:?}", error);
+                    let _ = content_reader.cancel();
+                    resume::remove_partial_files(&self.file_storage, &partial_files_id, files.len());
+                    resume::remove_state(&self.file_storage, &partial_files_id);
+                    completed = false;
+                    break;
+                }
+            };
+
+            let mut file_read: u64 = file_offset;
+
+            while file_read < manifest_entry.file_size {
+                if self.variables.blocking_read().should_cancel {
+                    let _ = content_reader.cancel();
+                    break;
+                }
+
+                let remaining = min(buffer.len() as u64, manifest_entry.file_size - file_read) as usize;
+
+                let read_size = match content_reader.read(&mut buffer[..remaining]) {
+                    Ok(ContentRead::Data(read_size)) if read_size > 0 => read_size,
+                    Ok(ContentRead::Cancelled) => {
+                        sender_cancelled = true;
+                        break;
+                    },
+                    _ => break
+                };
+
+                if let Err(error) = file.write_all(&buffer[..read_size]) {
+                    println!("Failed to write file to disk: {:?}", error);
+                    write_failed = true;
+                    let _ = content_reader.cancel();
+                    break;
+                }
+
+                content_hasher.update(&buffer[..read_size]);
+                file_read += read_size as u64;
+                all_read += read_size as u64;
+
+                self.update_progress(ReceiveProgressState::Receiving {
+                    progress: fraction(all_read, total_size),
+                    file_index: file_index as u32,
+                    file_progress: fraction(file_read, manifest_entry.file_size)
+                });
+            }
+
+            if file_read < manifest_entry.file_size {
+                completed = false;
 
-            file.write_all(&buffer[..read_size])
-                .expect("Failed to write file to disk");
+                if resumable && !sender_cancelled && !write_failed && !self.variables.blocking_read().should_cancel {
+                    let _ = file.sync_data();
+                    resume_state.offset = all_read;
+                    let _ = resume::save_state(&self.file_storage, &resume_state);
+                } else {
+                    resume::remove_partial_files(&self.file_storage, &partial_files_id, files.len());
+                    resume::remove_state(&self.file_storage, &partial_files_id);
+                }
 
-            let progress = all_read / file_transfer.file_size as f64;
-            self.update_progress(ReceiveProgressState::Receiving { progress });
+                break;
+            }
 
-            if all_read == file_transfer.file_size as f64 {
+            if file.sync_all().is_err() {
+                completed = false;
                 break;
             }
+
+            if manifest_entry.file_size == 0 {
+                self.update_progress(ReceiveProgressState::Receiving {
+                    progress: fraction(all_read, total_size),
+                    file_index: file_index as u32,
+                    file_progress: 1.0
+                });
+            }
+
+            file_start = file_end;
+        }
+
+        if completed {
+            completed = content_reader.finish().is_ok();
+        }
+
+        if completed {
+            completed = self.verify_integrity(&mut stream, &content_hasher);
+
+            if !completed {
+                println!("Received content does not match the sent content, deleting the received files");
+
+                resume::remove_partial_files(&self.file_storage, &partial_files_id, files.len());
+                resume::remove_state(&self.file_storage, &partial_files_id);
+            }
+        }
+
+        if completed {
+            completed = self.move_into_place(&files, &partial_files_id, &local_paths);
         }
 
+        let _ = stream.finish();
         stream.close();
 
-        if all_read < file_transfer.file_size as f64 {
-            let _ = fs::remove_file(path);
-            self.update_progress(ReceiveProgressState::Cancelled);
-        } else {
+        if completed {
+            resume::remove_state(&self.file_storage, &partial_files_id);
             self.update_progress(ReceiveProgressState::Finished);
+        } else {
+            // Created again, if the transfer is resumed
+            remove_directories(&created_directories);
+            self.update_progress(ReceiveProgressState::Cancelled);
+        }
+    }
+}
+
+/// Answers the transfer request with a decline and closes the connection.
+fn send_decline_response(mut stream: &mut dyn EncryptedReadWrite, decline_reason: DeclineReason, decline_message: Option<String>) {
+    let mut proto_stream = Stream::new(&mut stream);
+
+    let _ = proto_stream.send(&TransferRequestResponse {
+        accepted: false,
+        resume_offset: 0,
+        decline_reason: decline_reason as i32,
+        decline_message
+    });
+
+    let _ = stream.finish();
+    stream.close();
+}
+
+/// Files of the transfer, in the order they are sent.
+/// Requests that only contain a single file name and size are treated as a batch of one file.
+fn file_manifest(file_transfer: &FileTransferIntent) -> Vec<FileManifestEntry> {
+    if !file_transfer.files.is_empty() {
+        return file_transfer.files.clone();
+    }
+
+    return vec![FileManifestEntry {
+        file_name: file_transfer.file_name.clone().unwrap_or_else(|| DEFAULT_FILE_NAME.to_string()),
+        file_size: file_transfer.file_size,
+        modified: 0
+    }];
+}
+
+/// Creates the directories of a transfer, parents first, and returns the ones that did not exist before.
+/// If one of them cannot be created, the ones created so far are removed again.
+fn create_directories(file_storage: &str, directories: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut created_directories = vec![];
+
+    for directory in directories {
+        let path = Path::new(file_storage).join(directory);
+
+        if path.is_dir() {
+            continue;
+        }
+
+        if let Err(error) = fs::create_dir_all(&path) {
+            remove_directories(&created_directories);
+            return Err(error);
         }
+
+        created_directories.push(path);
+    }
+
+    return Ok(created_directories);
+}
+
+/// Removes directories created by `create_directories`, children first. Directories that are not empty are kept.
+fn remove_directories(directories: &[PathBuf]) {
+    for directory in directories.iter().rev() {
+        let _ = fs::remove_dir(directory);
     }
 }
+
+/// Opens the file for writing at `offset`. Anything after the offset is discarded.
+fn open_file(path: &Path, offset: u64) -> io::Result<File> {
+    if offset == 0 {
+        return File::create(path);
+    }
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(offset)?;
+    file.seek(SeekFrom::End(0))?;
+
+    return Ok(file);
+}
+
+/// Adds the first `length` bytes of an already received file to the hash.
+fn hash_received_part(content_hasher: &mut ContentHasher, path: &Path, length: u64) -> io::Result<()> {
+    let mut file = File::open(path)?;
+
+    return content_hasher.update_from_reader(&mut file, length);
+}
+
+/// Relative path, that consists only of regular path components separated by "/".
+fn is_safe_relative_path(path: &str) -> bool {
+    if path.is_empty() {
+        return false;
+    }
+
+    return path.split('/').all(|component| {
+        let mut components = Path::new(component).components();
+
+        return matches!((components.next(), components.next()), (Some(Component::Normal(normal)), None) if normal == component);
+    });
+}
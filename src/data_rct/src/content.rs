@@ -0,0 +1,198 @@
+use std::io::{self, Read, Write};
+use prost_stream::Stream;
+use protocol::communication::{TransferCancel, TransferControl, TransferData};
+use protocol::communication::transfer_control::Control;
+use protocol::communication::transfer_data::Content;
+
+/// Number of content bytes after which the receiver sends a `TransferControl` acknowledgement.
+/// The sender keeps writing while the acknowledgement of the previous window is on its way, so it never waits on a round trip.
+pub const CONTENT_WINDOW_SIZE: u64 = 256 * 1024;
+
+/// Result of writing the content of a transfer.
+#[derive(Debug, PartialEq)]
+pub enum ContentWrite {
+    Written,
+    /// The receiver cancelled the transfer. The sender has already confirmed the cancellation.
+    CancelledByReceiver
+}
+
+/// Result of reading the content of a transfer.
+pub enum ContentRead {
+    /// Number of bytes read into the buffer, 0 at the end of the stream.
+    Data(usize),
+    /// The sender cancelled the transfer.
+    Cancelled
+}
+
+/// Writes the content of a file or clipboard transfer as `TransferData` messages.
+/// The receiver acknowledges them with `TransferControl` messages, so both sides can cancel the transfer.
+pub struct ContentWriter<'a, TStream> where TStream: Read + Write {
+    stream: &'a mut TStream,
+    written: u64,
+    acknowledged_windows: u64
+}
+
+impl<'a, TStream> ContentWriter<'a, TStream> where TStream: Read + Write {
+    pub fn new(stream: &'a mut TStream) -> Self {
+        return Self {
+            stream,
+            written: 0,
+            acknowledged_windows: 0
+        };
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<ContentWrite> {
+        self.send(Content::Data(data.to_vec()))?;
+        self.written += data.len() as u64;
+
+        // Reads the acknowledgement of the window before the one that was just completed
+        while self.written / CONTENT_WINDOW_SIZE > self.acknowledged_windows + 1 {
+            if self.read_acknowledgement()? == ContentWrite::CancelledByReceiver {
+                return Ok(ContentWrite::CancelledByReceiver);
+            }
+        }
+
+        return Ok(ContentWrite::Written);
+    }
+
+    /// Waits until the receiver confirms the complete content,
+    /// so the next message from the receiver is the answer to whatever comes after the content.
+    pub fn finish(&mut self) -> io::Result<ContentWrite> {
+        loop {
+            match self.read_control()? {
+                Control::Acknowledged(_) => {},
+                Control::Completed(received) if received == self.written => return Ok(ContentWrite::Written),
+                Control::Completed(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Receiver did not receive the complete content")),
+                Control::Cancel(_) => {
+                    self.cancel()?;
+                    return Ok(ContentWrite::CancelledByReceiver);
+                }
+            }
+        }
+    }
+
+    /// Tells the receiver that no more content follows.
+    /// Waits until the receiver closes the connection. Closing it first with unread acknowledgements
+    /// would reset the connection, and the receiver could lose the cancellation.
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.send(Content::Cancel(TransferCancel {}))?;
+
+        while self.read_control().is_ok() {}
+
+        return Ok(());
+    }
+
+    fn read_acknowledgement(&mut self) -> io::Result<ContentWrite> {
+        return match self.read_control()? {
+            Control::Acknowledged(_) => {
+                self.acknowledged_windows += 1;
+                Ok(ContentWrite::Written)
+            },
+            Control::Completed(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Receiver completed before the content ended")),
+            Control::Cancel(_) => {
+                self.cancel()?;
+                Ok(ContentWrite::CancelledByReceiver)
+            }
+        };
+    }
+
+    fn read_control(&mut self) -> io::Result<Control> {
+        let mut proto_stream = Stream::new(&mut *self.stream);
+
+        let message = proto_stream.recv::<TransferControl>()
+            .map_err(|error| io::Error::new(io::ErrorKind::UnexpectedEof, error.to_string()))?;
+
+        return message.control
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty control message"));
+    }
+
+    fn send(&mut self, content: Content) -> io::Result<()> {
+        let mut proto_stream = Stream::new(&mut *self.stream);
+
+        return proto_stream.send(&TransferData { content: Some(content) })
+            .map_err(|error| io::Error::other(error.to_string()));
+    }
+}
+
+/// Reads content written by a `ContentWriter`.
+pub struct ContentReader<'a, TStream> where TStream: Read + Write {
+    stream: &'a mut TStream,
+    data: Vec<u8>,
+    position: usize,
+    received: u64,
+    acknowledged_windows: u64
+}
+
+impl<'a, TStream> ContentReader<'a, TStream> where TStream: Read + Write {
+    pub fn new(stream: &'a mut TStream) -> Self {
+        return Self {
+            stream,
+            data: Vec::new(),
+            position: 0,
+            received: 0,
+            acknowledged_windows: 0
+        };
+    }
+
+    pub fn read(&mut self, buffer: &mut [u8]) -> io::Result<ContentRead> {
+        while self.position >= self.data.len() {
+            match self.receive()? {
+                Some(data) => {
+                    self.data = data;
+                    self.position = 0;
+                },
+                None => return Ok(ContentRead::Cancelled)
+            }
+        }
+
+        let length = buffer.len().min(self.data.len() - self.position);
+        buffer[..length].copy_from_slice(&self.data[self.position..self.position + length]);
+        self.position += length;
+
+        return Ok(ContentRead::Data(length));
+    }
+
+    /// Confirms that the complete content was received.
+    pub fn finish(&mut self) -> io::Result<()> {
+        return self.send(Control::Completed(self.received));
+    }
+
+    /// Asks the sender to stop, and discards the content sent in the meantime until the sender confirms.
+    pub fn cancel(&mut self) -> io::Result<()> {
+        self.send(Control::Cancel(TransferCancel {}))?;
+
+        while self.receive()?.is_some() {}
+
+        return Ok(());
+    }
+
+    /// Next chunk of content, or `None` if the sender cancelled the transfer.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut proto_stream = Stream::new(&mut *self.stream);
+
+        let message = proto_stream.recv::<TransferData>()
+            .map_err(|error| io::Error::new(io::ErrorKind::UnexpectedEof, error.to_string()))?;
+
+        let data = match message.content {
+            Some(Content::Data(data)) => data,
+            Some(Content::Cancel(_)) => return Ok(None),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Empty content message"))
+        };
+
+        self.received += data.len() as u64;
+
+        while self.received / CONTENT_WINDOW_SIZE > self.acknowledged_windows {
+            self.acknowledged_windows += 1;
+            self.send(Control::Acknowledged(self.acknowledged_windows * CONTENT_WINDOW_SIZE))?;
+        }
+
+        return Ok(Some(data));
+    }
+
+    fn send(&mut self, control: Control) -> io::Result<()> {
+        let mut proto_stream = Stream::new(&mut *self.stream);
+
+        return proto_stream.send(&TransferControl { control: Some(control) })
+            .map_err(|error| io::Error::other(error.to_string()));
+    }
+}
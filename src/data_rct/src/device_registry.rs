@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use protocol::discovery::{Device, DeviceConnectionInfo};
+use crate::discovery::DiscoveryMethod;
+
+/// A discovered device, when it was seen last and over which medium.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredDevice {
+    pub device: Device,
+    /// Milliseconds since the Unix epoch
+    pub last_seen: u64,
+    pub medium: DiscoveryMethod
+}
+
+struct DiscoveredDeviceEntry {
+    connection_info: DeviceConnectionInfo,
+    last_seen: SystemTime,
+    medium: DiscoveryMethod
+}
+
+/// Devices found by a `Discovery`, and how to connect to them.
+/// A `NearbyServer` can only send to devices in the registry passed to `set_device_registry`.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: RwLock<HashMap<String, DiscoveredDeviceEntry>>
+}
+
+impl DeviceRegistry {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn get_devices(&self) -> Vec<Device> {
+        return self.devices.read().expect("Failed to lock devices").values()
+            .filter_map(|entry| entry.connection_info.device.clone())
+            .collect();
+    }
+
+    pub fn get_discovered_devices(&self) -> Vec<DiscoveredDevice> {
+        return self.devices.read().expect("Failed to lock devices").values()
+            .filter_map(|entry| Some(DiscoveredDevice {
+                device: entry.connection_info.device.clone()?,
+                last_seen: entry.last_seen.duration_since(UNIX_EPOCH).map(|duration| duration.as_millis() as u64).unwrap_or(0),
+                medium: entry.medium
+            }))
+            .collect();
+    }
+
+    pub fn get_connection_details(&self, device_id: &str) -> Option<DeviceConnectionInfo> {
+        return self.devices.read().expect("Failed to lock devices").get(device_id).map(|entry| entry.connection_info.clone());
+    }
+
+    /// Whether `identity_public_key` is the key the device advertised, `None` if the device is unknown.
+    /// Only a device last seen over BLE may have advertised no key, as older versions do not send one.
+    pub fn matches_identity(&self, device_id: &str, identity_public_key: &[u8]) -> Option<bool> {
+        let devices = self.devices.read().expect("Failed to lock devices");
+        let entry = devices.get(device_id)?;
+        let advertised_key = &entry.connection_info.identity_public_key;
+
+        if advertised_key.is_empty() {
+            return Some(!entry.medium.carries_identity_key());
+        }
+
+        return Some(advertised_key.as_slice() == identity_public_key);
+    }
+
+    /// Stores the details of a device, that was just seen. Returns whether the device is new or its details changed.
+    /// mDNS does not carry BLE details, so the BLE details of a device seen over BLE as well are kept.
+    /// A known identity key is kept as well, so an advertisement without a key cannot remove it.
+    pub fn update(&self, mut connection_info: DeviceConnectionInfo, medium: DiscoveryMethod) -> bool {
+        let Some(device) = &connection_info.device else {
+            return false;
+        };
+
+        let device_id = device.id.clone();
+        let mut devices = self.devices.write().expect("Failed to lock devices");
+
+        if let Some(previous) = devices.get(&device_id) {
+            if connection_info.ble.is_none() {
+                connection_info.ble = previous.connection_info.ble.clone();
+            }
+
+            if connection_info.identity_public_key.is_empty() {
+                connection_info.identity_public_key = previous.connection_info.identity_public_key.clone();
+            }
+        }
+
+        let previous = devices.insert(device_id, DiscoveredDeviceEntry {
+            connection_info: connection_info.clone(),
+            last_seen: SystemTime::now(),
+            medium
+        });
+
+        return previous.map(|previous| previous.connection_info) != Some(connection_info);
+    }
+
+    /// Removes the device, if it was last seen over `medium`, as it may still be reachable over another medium.
+    /// Returns whether the device was removed.
+    pub fn remove(&self, device_id: &str, medium: DiscoveryMethod) -> bool {
+        let mut devices = self.devices.write().expect("Failed to lock devices");
+
+        if devices.get(device_id).is_none_or(|entry| entry.medium != medium) {
+            return false;
+        }
+
+        return devices.remove(device_id).is_some();
+    }
+
+    /// Removes devices not seen for longer than `device_ttl`, and returns their ids.
+    /// Devices last seen via mDNS are kept, as mDNS removes them itself once their records expire.
+    pub fn remove_expired(&self, device_ttl: Duration) -> Vec<String> {
+        let mut expired_device_ids = vec![];
+
+        self.devices.write().expect("Failed to lock devices").retain(|device_id, entry| {
+            // A clock that went backwards does not expire anything
+            let expired = entry.medium != DiscoveryMethod::Mdns
+                && entry.last_seen.elapsed().is_ok_and(|elapsed| elapsed > device_ttl);
+
+            if expired {
+                expired_device_ids.push(device_id.clone());
+            }
+
+            return !expired;
+        });
+
+        return expired_device_ids;
+    }
+
+    pub fn clear(&self) {
+        self.devices.write().expect("Failed to lock devices").clear();
+    }
+}
@@ -1,30 +1,75 @@
-use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
 use protocol::DiscoveryDelegate;
 use protocol::discovery::{DeviceConnectionInfo, DeviceDiscoveryMessage, Device};
 use protocol::discovery::device_discovery_message::Content;
 use protocol::prost::Message;
+use crate::device_registry::{DeviceRegistry, DiscoveredDevice};
 use crate::errors::DiscoverySetupError;
 use crate::init_logger;
+use crate::mdns::MdnsBrowser;
+use crate::udp::UdpBrowser;
 
 pub trait BleDiscoveryImplementationDelegate: Send + Sync + Debug {
     fn start_scanning(&self);
     fn stop_scanning(&self);
 }
 
-static DISCOVERED_DEVICES: OnceLock<RwLock<HashMap<String, DeviceConnectionInfo>>> = OnceLock::new();
+/// Ways `Discovery::start` can look for devices.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiscoveryMethod {
+    /// Scans via the native `BleDiscoveryImplementationDelegate`
+    BLE,
+    /// Browses for DNS-SD services on the local network
+    Mdns,
+    /// Asks and listens on a UDP multicast group, for networks that filter mDNS
+    Udp
+}
+
+impl DiscoveryMethod {
+    /// mDNS and UDP were added together with identity keys, so a device found over them always advertises one.
+    pub(crate) fn carries_identity_key(&self) -> bool {
+        return *self != DiscoveryMethod::BLE;
+    }
+}
+
+/// Devices not seen for this long are removed, unless `Discovery::set_device_ttl` changes it.
+/// Three times the UDP announce interval, so a single lost announcement does not remove a device.
+pub const DEFAULT_DEVICE_TTL: Duration = Duration::from_secs(90);
+
+/// How often expired devices are looked for.
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+type SharedDiscoveryDelegate = Option<Arc<Mutex<Box<dyn DiscoveryDelegate>>>>;
+
+/// Keeps the device registry up to date and tells the delegate about the changes.
+/// Cloned into the threads of the discovery methods.
+#[derive(Clone)]
+struct DeviceListUpdater {
+    device_registry: Arc<DeviceRegistry>,
+    discovery_delegate: SharedDiscoveryDelegate
+}
 
 pub struct Discovery {
     pub ble_discovery_implementation: Option<Box<dyn BleDiscoveryImplementationDelegate>>,
-    discovery_delegate: Option<Arc<Mutex<Box<dyn DiscoveryDelegate>>>>
+    device_list_updater: DeviceListUpdater,
+    mdns_browser: Mutex<Option<MdnsBrowser>>,
+    udp_browser: Mutex<Option<UdpBrowser>>,
+    device_ttl: Arc<AtomicU64>,
+    expiry_running: Mutex<Option<Arc<AtomicBool>>>
 }
 
 impl Discovery {
     pub fn new(delegate: Option<Box<dyn DiscoveryDelegate>>) -> Result<Self, DiscoverySetupError> {
-        init_logger();
+        return Discovery::with_device_registry(Arc::new(DeviceRegistry::new()), delegate);
+    }
 
-        DISCOVERED_DEVICES.get_or_init(|| RwLock::new(HashMap::new()));
+    /// Stores the discovered devices in `device_registry`, e.g. one shared with a `Discovery` that is replaced.
+    pub fn with_device_registry(device_registry: Arc<DeviceRegistry>, delegate: Option<Box<dyn DiscoveryDelegate>>) -> Result<Self, DiscoverySetupError> {
+        init_logger();
 
         let callback_arc = match delegate {
             Some(callback) => Some(Arc::new(Mutex::new(callback))),
@@ -33,37 +78,65 @@ impl Discovery {
 
         Ok(Self {
             ble_discovery_implementation: None,
-            discovery_delegate: callback_arc
+            device_list_updater: DeviceListUpdater {
+                device_registry,
+                discovery_delegate: callback_arc
+            },
+            mdns_browser: Mutex::new(None),
+            udp_browser: Mutex::new(None),
+            device_ttl: Arc::new(AtomicU64::new(DEFAULT_DEVICE_TTL.as_secs())),
+            expiry_running: Mutex::new(None)
         })
     }
 
     pub fn get_devices(&self) -> Vec<Device> {
-        let mut devices = vec![];
-
-        for device in DISCOVERED_DEVICES.get().unwrap().read().unwrap().iter() {
-            devices.push(device.1.clone().device.expect("No device in DeviceConnectionInfo"));
-        }
+        return self.device_list_updater.device_registry.get_devices();
+    }
 
-        return devices
+    pub fn get_discovered_devices(&self) -> Vec<DiscoveredDevice> {
+        return self.device_list_updater.device_registry.get_discovered_devices();
     }
 
-    pub fn get_connection_details(device: Device) -> Option<DeviceConnectionInfo> {
-        if DISCOVERED_DEVICES.get().unwrap().read().unwrap().contains_key(&device.id) {
-            return Some(DISCOVERED_DEVICES.get().unwrap().read().unwrap()[&device.id].clone());
-        }
+    /// Pass it to `NearbyServer::set_device_registry`, so the server can connect to the discovered devices.
+    pub fn get_device_registry(&self) -> Arc<DeviceRegistry> {
+        return self.device_list_updater.device_registry.clone();
+    }
 
-        return None;
+    /// Devices, that were not seen for `seconds`, are removed and reported to `device_removed`. Zero disables the expiry.
+    /// Devices last seen via mDNS are removed by mDNS itself, once their records expire.
+    pub fn set_device_ttl(&self, seconds: u64) {
+        self.device_ttl.store(seconds, Ordering::Relaxed);
     }
 
     pub fn add_ble_implementation(&mut self, implementation: Box<dyn BleDiscoveryImplementationDelegate>) {
         self.ble_discovery_implementation = Some(implementation)
     }
 
-    pub fn start(&self) {
-        DISCOVERED_DEVICES.get().unwrap().write().unwrap().clear();
+    /// Methods, that fail to start, are skipped, so the others still find devices.
+    /// Devices already in the registry, e.g. found before a restart or by another `Discovery` sharing it, are reported to `device_added` again.
+    pub fn start(&self, methods: Vec<DiscoveryMethod>) {
+        for device in self.device_list_updater.device_registry.get_devices() {
+            self.device_list_updater.add_discovered_device(device);
+        }
 
-        if let Some(ble_discovery_implementation) = &self.ble_discovery_implementation {
-            ble_discovery_implementation.start_scanning();
+        self.start_expiry();
+
+        if methods.contains(&DiscoveryMethod::BLE) {
+            if let Some(ble_discovery_implementation) = &self.ble_discovery_implementation {
+                ble_discovery_implementation.start_scanning();
+            }
+        }
+
+        if methods.contains(&DiscoveryMethod::Mdns) {
+            if let Err(error) = self.start_mdns() {
+                println!("Unable to start mDNS discovery: {:?}", error);
+            }
+        }
+
+        if methods.contains(&DiscoveryMethod::Udp) {
+            if let Err(error) = self.start_udp() {
+                println!("Unable to start UDP discovery: {:?}", error);
+            }
         }
     }
 
@@ -71,9 +144,97 @@ impl Discovery {
         if let Some(ble_discovery_implementation) = &self.ble_discovery_implementation {
             ble_discovery_implementation.stop_scanning();
         }
+
+        *self.mdns_browser.lock().expect("Failed to lock mdns_browser") = None;
+        *self.udp_browser.lock().expect("Failed to lock udp_browser") = None;
+
+        if let Some(expiry_running) = self.expiry_running.lock().expect("Failed to lock expiry_running").take() {
+            expiry_running.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn start_expiry(&self) {
+        let mut expiry_running = self.expiry_running.lock().expect("Failed to lock expiry_running");
+
+        if expiry_running.is_some() {
+            return;
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let device_ttl = self.device_ttl.clone();
+        let device_list_updater = self.device_list_updater.clone();
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep(EXPIRY_CHECK_INTERVAL);
+
+                // Stopped while sleeping
+                if !thread_running.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let device_ttl = device_ttl.load(Ordering::Relaxed);
+
+                if device_ttl > 0 {
+                    device_list_updater.remove_expired_devices(Duration::from_secs(device_ttl));
+                }
+            }
+        });
+
+        *expiry_running = Some(running);
+    }
+
+    /// Browses the local network for devices advertised by a `NearbyServer`.
+    fn start_mdns(&self) -> Result<(), DiscoverySetupError> {
+        let mut mdns_browser = self.mdns_browser.lock().expect("Failed to lock mdns_browser");
+
+        if mdns_browser.is_some() {
+            return Ok(());
+        }
+
+        let found_updater = self.device_list_updater.clone();
+        let lost_updater = self.device_list_updater.clone();
+
+        *mdns_browser = Some(MdnsBrowser::start(
+            move |device_connection_info| found_updater.update_device(device_connection_info, DiscoveryMethod::Mdns),
+            move |device_id| lost_updater.remove_device(device_id, DiscoveryMethod::Mdns)
+        )?);
+
+        return Ok(());
+    }
+
+    /// Listens for announcements of `NearbyServer`s on the UDP multicast group.
+    fn start_udp(&self) -> Result<(), DiscoverySetupError> {
+        let mut udp_browser = self.udp_browser.lock().expect("Failed to lock udp_browser");
+
+        if udp_browser.is_some() {
+            return Ok(());
+        }
+
+        let device_list_updater = self.device_list_updater.clone();
+
+        *udp_browser = Some(UdpBrowser::start(
+            move |data| device_list_updater.handle_discovery_message(data, None, DiscoveryMethod::Udp)
+        )?);
+
+        return Ok(());
     }
 
     pub fn parse_discovery_message(&mut self, data: Vec<u8>, ble_uuid: Option<String>) {
+        self.device_list_updater.handle_discovery_message(data, ble_uuid, DiscoveryMethod::BLE);
+    }
+}
+
+/// The browsers stop with the `Discovery`, so the expiry does as well.
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl DeviceListUpdater {
+    fn handle_discovery_message(&self, data: Vec<u8>, ble_uuid: Option<String>, medium: DiscoveryMethod) {
         let discovery_message = DeviceDiscoveryMessage::decode_length_delimited(data.as_slice());
 
         let Ok(discovery_message) = discovery_message else {
@@ -81,12 +242,8 @@ impl Discovery {
         };
 
         match discovery_message.content {
-            None => {}
+            None | Some(Content::Query(_)) => {}
             Some(Content::DeviceConnectionInfo(device_connection_info)) => {
-                let Some(device) = &device_connection_info.device else {
-                    return;
-                };
-
                 let mut device_connection_info = device_connection_info.clone();
 
                 if let Some(ble_uuid) = ble_uuid {
@@ -96,23 +253,38 @@ impl Discovery {
                     }
                 }
 
-                if DISCOVERED_DEVICES.get().unwrap().write().unwrap().contains_key(&device.id) {
-                    if !DISCOVERED_DEVICES.get().unwrap().write().unwrap().get(&device.id).unwrap().eq(&device_connection_info) {
-                        self.add_discovered_device(device.clone());
-                    }
-                } else {
-                    self.add_discovered_device(device.clone());
-                }
-
-                DISCOVERED_DEVICES.get().unwrap().write().unwrap().insert(device.id.clone(), device_connection_info.clone());
+                self.update_device(device_connection_info, medium);
             }
             Some(Content::OfflineDeviceId(device_id)) => {
-                DISCOVERED_DEVICES.get().unwrap().write().unwrap().remove(&device_id);
-                self.remove_discovered_device(device_id);
+                self.remove_device(device_id, medium);
             }
         };
     }
 
+    /// Tells the delegate, if the device is new or its details changed.
+    fn update_device(&self, device_connection_info: DeviceConnectionInfo, medium: DiscoveryMethod) {
+        let Some(device) = device_connection_info.device.clone() else {
+            return;
+        };
+
+        if self.device_registry.update(device_connection_info, medium) {
+            self.add_discovered_device(device);
+        }
+    }
+
+    /// Handles a device, that went offline on `medium`.
+    fn remove_device(&self, device_id: String, medium: DiscoveryMethod) {
+        if self.device_registry.remove(&device_id, medium) {
+            self.remove_discovered_device(device_id);
+        }
+    }
+
+    fn remove_expired_devices(&self, device_ttl: Duration) {
+        for device_id in self.device_registry.remove_expired(device_ttl) {
+            self.remove_discovered_device(device_id);
+        }
+    }
+
     fn add_discovered_device(&self, device: Device) {
         if let Some(discovery_delegate) = &self.discovery_delegate {
             discovery_delegate.lock().expect("Failed to lock discovery_delegate").device_added(device);
@@ -1,71 +1,221 @@
+use std::cmp::min;
 use std::io;
 use std::io::{Error, Read, Write};
-use std::io::ErrorKind::Other;
-use std::iter::repeat;
-use rand_core::OsRng;
-use chacha20::XChaCha20;
-use chacha20::cipher::{KeyIvInit, StreamCipher};
+use std::io::ErrorKind::{InvalidData, Other, UnexpectedEof};
+use rand_core::{OsRng, RngCore};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
 
+use crate::negotiation::NegotiatedProtocol;
 use crate::stream::Close;
 
+/// XChaCha20 nonce (24 bytes) minus the 5 bytes used by the STREAM counter and last-block flag.
+pub const NONCE_PREFIX_SIZE: usize = 19;
+
+/// Maximum amount of plaintext sealed into a single frame.
+pub const MAX_FRAME_PAYLOAD_SIZE: usize = 64 * 1024;
+
+const TAG_SIZE: usize = 16;
+const LAST_FRAME_FLAG: u32 = 1 << 31;
+
 pub fn generate_key() -> [u8; 32] {
-    let key = XChaCha20::generate_key(&mut OsRng);
+    let key = XChaCha20Poly1305::generate_key(&mut OsRng);
 
     return key.into();
 }
 
-pub fn generate_iv() -> [u8; 24] {
-    let nonce = XChaCha20::generate_iv(&mut OsRng);
+pub fn generate_iv() -> [u8; NONCE_PREFIX_SIZE] {
+    let mut nonce = [0u8; NONCE_PREFIX_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+
+    return nonce;
+}
+
+/// Keys and nonce prefixes for both directions of a connection.
+/// The send key of one side is the receive key of the other side.
+pub struct SessionKeys {
+    pub send_key: [u8; 32],
+    pub send_iv: [u8; NONCE_PREFIX_SIZE],
+    pub receive_key: [u8; 32],
+    pub receive_iv: [u8; NONCE_PREFIX_SIZE]
+}
 
-    return nonce.into();
+/// Result of a successful handshake.
+#[derive(Clone)]
+pub struct HandshakeDetails {
+    /// Long-term identity key of the other side, authenticated by the handshake.
+    pub peer_identity_public_key: [u8; 32],
+    pub transcript_hash: [u8; 32],
+    /// Short code derived from the transcript. Both sides only show the same code if nobody tampered with the handshake.
+    pub verification_code: String,
+    pub negotiated_protocol: NegotiatedProtocol
 }
 
+/// Authenticated stream using the XChaCha20Poly1305 STREAM construction.
+///
+/// Every write is sealed into its own frame (`u32` big endian header followed by the ciphertext).
+/// The most significant bit of the header marks the last frame, which is written by `finish()`.
+/// Reading a frame that fails to authenticate, or reaching the end of the raw stream before the
+/// last frame, results in an `io::Error` and leaves the stream unusable.
+///
+/// Each direction uses its own cipher, so both sides can write without reusing a keystream.
 pub struct EncryptedStream<TStream> where TStream : Read + Write {
-    pub cipher: XChaCha20,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    received_last_frame: bool,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+    pub handshake_details: Option<HandshakeDetails>,
     pub raw_stream: TStream
 }
 
 impl<TStream> EncryptedStream<TStream> where TStream : Read + Write {
-    pub fn new(key: [u8; 32], iv: [u8; 24], stream: TStream) -> Self {
-        let cipher = XChaCha20::new(&key.into(), &iv.into());
+    pub fn new(keys: SessionKeys, stream: TStream) -> Self {
+        let encryptor = EncryptorBE32::new(&keys.send_key.into(), &keys.send_iv.into());
+        let decryptor = DecryptorBE32::new(&keys.receive_key.into(), &keys.receive_iv.into());
 
         Self {
-            cipher,
+            encryptor: Some(encryptor),
+            decryptor: Some(decryptor),
+            received_last_frame: false,
+            read_buffer: Vec::new(),
+            read_position: 0,
+            handshake_details: None,
             raw_stream: stream
         }
     }
+
+    /// Writes the last frame, so the other side can tell a finished stream apart from a truncated one.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let Some(encryptor) = self.encryptor.take() else {
+            return Ok(());
+        };
+
+        let ciphertext = match encryptor.encrypt_last(&[][..]) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err(Error::new(Other, "Failed to encrypt last frame"))
+        };
+
+        self.write_frame(&ciphertext, true)?;
+
+        return self.raw_stream.flush();
+    }
+
+    fn write_frame(&mut self, ciphertext: &[u8], is_last: bool) -> io::Result<()> {
+        let mut header = ciphertext.len() as u32;
+
+        if is_last {
+            header |= LAST_FRAME_FLAG;
+        }
+
+        let mut frame = Vec::with_capacity(4 + ciphertext.len());
+        frame.extend_from_slice(&header.to_be_bytes());
+        frame.extend_from_slice(ciphertext);
+
+        return self.raw_stream.write_all(&frame);
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 4];
+
+        if let Err(error) = self.raw_stream.read_exact(&mut header) {
+            self.decryptor = None;
+
+            if error.kind() == UnexpectedEof {
+                return Err(Error::new(UnexpectedEof, "Encrypted stream was truncated"));
+            }
+
+            return Err(error);
+        }
+
+        let header = u32::from_be_bytes(header);
+        let is_last = header & LAST_FRAME_FLAG != 0;
+        let length = (header & !LAST_FRAME_FLAG) as usize;
+
+        if length > MAX_FRAME_PAYLOAD_SIZE + TAG_SIZE {
+            self.decryptor = None;
+            return Err(Error::new(InvalidData, "Encrypted frame exceeds the maximum frame size"));
+        }
+
+        let mut ciphertext = vec![0u8; length];
+
+        if let Err(error) = self.raw_stream.read_exact(&mut ciphertext) {
+            self.decryptor = None;
+            return Err(error);
+        }
+
+        let Some(mut decryptor) = self.decryptor.take() else {
+            return Err(Error::new(InvalidData, "Encrypted stream is no longer readable"));
+        };
+
+        let plaintext = if is_last {
+            self.received_last_frame = true;
+            decryptor.decrypt_last(ciphertext.as_slice())
+        } else {
+            let plaintext = decryptor.decrypt_next(ciphertext.as_slice());
+            self.decryptor = Some(decryptor);
+
+            plaintext
+        };
+
+        let Ok(plaintext) = plaintext else {
+            self.decryptor = None;
+            self.received_last_frame = false;
+
+            return Err(Error::new(InvalidData, "Failed to authenticate encrypted frame"));
+        };
+
+        self.read_buffer = plaintext;
+        self.read_position = 0;
+
+        return Ok(());
+    }
 }
 
 impl<TStream> Read for EncryptedStream<TStream> where TStream : Read + Write {
     fn read(&mut self, read_buffer: &mut [u8]) -> io::Result<usize> {
-        let mut buffer: Vec<u8> = repeat(0).take(read_buffer.len()).collect();
-        let read_bytes = self.raw_stream.read(&mut buffer).expect("Failed to read from encrypted buffer");
+        while self.read_position >= self.read_buffer.len() {
+            if self.decryptor.is_none() {
+                if self.received_last_frame {
+                    return Ok(0);
+                }
 
-        if read_bytes <= 0 {
-            return Ok(0);
+                return Err(Error::new(InvalidData, "Encrypted stream is no longer readable"));
+            }
+
+            self.read_frame()?;
         }
 
-        match self.cipher.apply_keystream_b2b(&buffer[..read_bytes], &mut read_buffer[..read_bytes]) {
-            Ok(_) => {}
-            Err(error) => return Err(Error::new(Other, error.to_string()))
-        };
+        let available = &self.read_buffer[self.read_position..];
+        let length = min(available.len(), read_buffer.len());
 
-        return Ok(read_bytes);
+        read_buffer[..length].copy_from_slice(&available[..length]);
+        self.read_position += length;
+
+        return Ok(length);
     }
 }
 
 impl<TStream> Write for EncryptedStream<TStream> where TStream : Read + Write {
     fn write(&mut self, write_buffer: &[u8]) -> io::Result<usize> {
-        let mut buffer: Vec<u8> = repeat(0).take(write_buffer.len()).collect();
-        let ciphertext = self.cipher.apply_keystream_b2b(write_buffer, &mut buffer);
-
-        if let Ok(()) = ciphertext {
-            if let Ok(written_bytes) = self.raw_stream.write(&buffer) {
-                return Ok(written_bytes);
-            }
+        if write_buffer.is_empty() {
+            return Ok(0);
         }
 
-        return Ok(0);
+        let Some(encryptor) = &mut self.encryptor else {
+            return Err(Error::new(Other, "Encrypted stream has already been finished"));
+        };
+
+        let plaintext = &write_buffer[..min(write_buffer.len(), MAX_FRAME_PAYLOAD_SIZE)];
+
+        let ciphertext = match encryptor.encrypt_next(plaintext) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return Err(Error::new(Other, "Failed to encrypt frame"))
+        };
+
+        self.write_frame(&ciphertext, false)?;
+
+        return Ok(plaintext.len());
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -79,5 +229,17 @@ impl<TStream> Close for EncryptedStream<TStream> where TStream: Close + Read + W
     }
 }
 
-pub trait EncryptedReadWrite: Read + Write + Send + Close {}
-impl<TStream> EncryptedReadWrite for EncryptedStream<TStream> where TStream : Read + Write + Send + Close {}
+pub trait EncryptedReadWrite: Read + Write + Send + Close {
+    fn finish(&mut self) -> io::Result<()>;
+    fn handshake_details(&self) -> Option<HandshakeDetails>;
+}
+
+impl<TStream> EncryptedReadWrite for EncryptedStream<TStream> where TStream : Read + Write + Send + Close {
+    fn finish(&mut self) -> io::Result<()> {
+        return EncryptedStream::finish(self);
+    }
+
+    fn handshake_details(&self) -> Option<HandshakeDetails> {
+        return self.handshake_details.clone();
+    }
+}
@@ -37,8 +37,68 @@ pub enum ConnectErrors {
     #[error("Failed to determine file size: {error}")]
     FailedToDetermineFileSize { error: String },
 
+    #[error("Failed to read directory: {error}")]
+    FailedToReadDirectory { error: String },
+
+    #[error("Failed to read file: {error}")]
+    FailedToReadFile { error: String },
+
     #[error("Failed to get transfer request response: {error}")]
     FailedToGetTransferRequestResponse { error: String },
+
+    #[error("Failed to get the receiver's confirmation of the transfer: {error}")]
+    FailedToGetTransferAck { error: String },
+
+    #[error("The received content does not match what was sent")]
+    IntegrityCheckFailed,
+
+    #[error("The receiver did not answer the request in time")]
+    RequestExpired,
+
+    #[error("A file with the same name already exists on the receiver")]
+    FileAlreadyExists,
+
+    #[error("The transfer is larger than the receiver allows")]
+    TransferTooLarge,
+
+    #[error("The receiver does not have enough free space")]
+    InsufficientStorage,
+
+    #[error("The receiver is busy")]
+    ReceiverBusy,
+
+    #[error("The receiver does not accept transfers from this device")]
+    UntrustedSender,
+
+    #[error("The receiver does not support this kind of transfer")]
+    UnsupportedIntent,
+
+    #[error("The receiver's auto accept policy declined the transfer")]
+    DeclinedByPolicy,
+
+    #[error("The receiver refused the request as invalid")]
+    InvalidRequest,
+
+    #[error("No device registry was set with set_device_registry")]
+    NoDeviceRegistry,
+
+    #[error("Invalid clipboard content: {error}")]
+    InvalidClipboardContent { error: String },
+
+    #[error("The receiver's identity does not match the advertised device")]
+    InvalidReceiverIdentity,
+
+    #[error("The verification code was not confirmed")]
+    VerificationRejected,
+
+    #[error("The send was cancelled")]
+    Cancelled,
+
+    #[error("The receiver uses an incompatible protocol version")]
+    IncompatibleProtocolVersion,
+
+    #[error("The receiver does not support this kind of transfer")]
+    UnsupportedByReceiver,
 }
 
 #[derive(Error, Debug)]
@@ -70,6 +130,15 @@ pub enum IncomingErrors {
     #[error("Encryption error")]
     EncryptionError,
 
+    #[error("Unsupported encryption mode")]
+    UnsupportedEncryptionMode,
+
+    #[error("Unsupported handshake mode")]
+    UnsupportedHandshakeMode,
+
+    #[error("Revealed verification nonce does not match the commitment")]
+    InvalidVerificationCommitment,
+
     #[error("Invalid sender-id")]
     InvalidSenderId,
 
@@ -80,6 +149,15 @@ pub enum IncomingErrors {
     Rejected,
 }
 
+#[derive(Error, Debug)]
+pub enum PairingErrors {
+    #[error("Invalid identity key")]
+    InvalidIdentityKey,
+
+    #[error("Failed to persist trusted devices: {error}")]
+    FailedToPersist { error: String }
+}
+
 #[derive(Error, Debug)]
 pub enum DiscoverySetupError {
     #[error("Unable to setup UDP Discovery")]
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Characters, that are not allowed in file names on at least one supported platform.
+const RESERVED_CHARACTERS: &str = "/\\:*?\"<>|";
+
+/// Device names reserved by Windows, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"
+];
+
+/// Maximum length of a single file name in bytes on common file systems.
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+/// Used if nothing is left of a name after sanitizing it.
+const EMPTY_NAME_REPLACEMENT: &str = "_";
+
+/// What happens when a received file or directory already exists in the file storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FileCollisionStrategy {
+    /// Stores the entry as "name (1).ext", using the first number that is not taken yet.
+    #[default]
+    Rename,
+    Overwrite,
+    /// Declines the transfer.
+    Fail
+}
+
+/// Where the files and directories of a transfer are stored, relative to the file storage.
+/// Same order as in the request.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalPaths {
+    pub files: Vec<String>,
+    pub directories: Vec<String>
+}
+
+/// Makes a single file name safe to use on all platforms.
+/// Control characters are removed, separators and other reserved characters are replaced by "_",
+/// and reserved device names like "CON" are prefixed with "_".
+pub fn sanitize_file_name(file_name: &str) -> String {
+    let sanitized: String = file_name.chars()
+        .filter(|character| !character.is_control())
+        .map(|character| if RESERVED_CHARACTERS.contains(character) { '_' } else { character })
+        .collect();
+
+    // Windows ignores trailing dots and spaces, so "CON." would still be a device
+    let mut sanitized = sanitized.trim_start().trim_end_matches(['.', ' ']).to_string();
+
+    if sanitized.is_empty() {
+        return EMPTY_NAME_REPLACEMENT.to_string();
+    }
+
+    let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+
+    if RESERVED_NAMES.iter().any(|reserved_name| reserved_name.eq_ignore_ascii_case(stem)) {
+        sanitized.insert(0, '_');
+    }
+
+    if sanitized.len() > MAX_FILE_NAME_LENGTH {
+        let mut end = MAX_FILE_NAME_LENGTH;
+
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        sanitized.truncate(end);
+    }
+
+    return sanitized;
+}
+
+/// Sanitizes every component of a relative path separated by "/". Empty and "." components are dropped.
+pub fn sanitize_path(path: &str) -> String {
+    let components: Vec<String> = path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .map(sanitize_file_name)
+        .collect();
+
+    if components.is_empty() {
+        return EMPTY_NAME_REPLACEMENT.to_string();
+    }
+
+    return components.join("/");
+}
+
+/// "name (1).ext" for `number` 1. Names starting with a dot, like ".profile", have no extension.
+pub fn numbered_file_name(file_name: &str, number: u32) -> String {
+    return match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{} ({}).{}", stem, number, extension),
+        _ => format!("{} ({})", file_name, number)
+    };
+}
+
+fn top_level_entry(path: &str) -> &str {
+    return path.split('/').next().unwrap_or(path);
+}
+
+/// Sanitizes the paths of a transfer and resolves collisions with existing entries of the file storage.
+/// Collisions are resolved for the top level entries, so a received directory is renamed as a whole.
+/// Returns the name of the existing entry as error, if the strategy is `FileCollisionStrategy::Fail`.
+/// Files of the transfer with the same name, e.g. from different directories of the sender, are always numbered.
+pub fn resolve_local_paths(file_storage: &str, files: &[String], directories: &[String], strategy: FileCollisionStrategy) -> Result<LocalPaths, String> {
+    let files: Vec<String> = files.iter().map(|file| sanitize_path(file)).collect();
+    let directories: Vec<String> = directories.iter().map(|directory| sanitize_path(directory)).collect();
+    let mut local_entries: HashMap<String, String> = HashMap::new();
+
+    for path in files.iter().chain(directories.iter()) {
+        let entry = top_level_entry(path);
+
+        if local_entries.contains_key(entry) {
+            continue;
+        }
+
+        let is_taken = |name: &str| Path::new(file_storage).join(name).exists()
+            || local_entries.values().any(|local_entry| local_entry == name);
+
+        let local_entry = match strategy {
+            FileCollisionStrategy::Overwrite => entry.to_string(),
+            _ if !is_taken(entry) => entry.to_string(),
+            FileCollisionStrategy::Fail => return Err(entry.to_string()),
+            FileCollisionStrategy::Rename => {
+                let mut number = 1;
+
+                while is_taken(&numbered_file_name(entry, number)) {
+                    number += 1;
+                }
+
+                numbered_file_name(entry, number)
+            }
+        };
+
+        local_entries.insert(entry.to_string(), local_entry);
+    }
+
+    let to_local_path = |path: &String| {
+        let entry = top_level_entry(path);
+        return format!("{}{}", local_entries[entry], &path[entry.len()..]);
+    };
+
+    let directories: Vec<String> = directories.iter().map(to_local_path).collect();
+    let files = number_duplicate_files(file_storage, files.iter().map(to_local_path).collect(), &directories);
+
+    return Ok(LocalPaths {
+        files,
+        directories
+    });
+}
+
+/// Numbers every file, whose path is already used by an earlier file or a directory of the transfer.
+fn number_duplicate_files(file_storage: &str, files: Vec<String>, directories: &[String]) -> Vec<String> {
+    let mut taken: HashSet<String> = files.iter().chain(directories.iter()).cloned().collect();
+    let mut used: HashSet<String> = directories.iter().cloned().collect();
+    let mut local_files = Vec::with_capacity(files.len());
+
+    for file in files {
+        if used.insert(file.clone()) {
+            local_files.push(file);
+            continue;
+        }
+
+        let (parent, file_name) = match file.rsplit_once('/') {
+            Some((parent, file_name)) => (format!("{}/", parent), file_name.to_string()),
+            None => (String::new(), file.clone())
+        };
+
+        let mut number = 1;
+
+        let numbered_file = loop {
+            let numbered_file = format!("{}{}", parent, numbered_file_name(&file_name, number));
+
+            if !taken.contains(&numbered_file) && !Path::new(file_storage).join(&numbered_file).exists() {
+                break numbered_file;
+            }
+
+            number += 1;
+        };
+
+        taken.insert(numbered_file.clone());
+        used.insert(numbered_file.clone());
+        local_files.push(numbered_file);
+    }
+
+    return local_files;
+}
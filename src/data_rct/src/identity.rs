@@ -0,0 +1,86 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::io::ErrorKind::InvalidData;
+use std::path::Path;
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const IDENTITY_FILE_NAME: &str = "identity.key";
+
+/// Long-term X25519 key pair of this device.
+/// It is mixed into every handshake, so peers can verify which device they are talking to.
+pub struct DeviceIdentity {
+    secret: StaticSecret,
+    public_key: PublicKey
+}
+
+impl DeviceIdentity {
+    pub fn generate() -> Self {
+        return Self::from_secret(StaticSecret::random_from_rng(OsRng));
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public_key = PublicKey::from(&secret);
+
+        return Self {
+            secret,
+            public_key
+        };
+    }
+
+    /// Loads the identity stored in `config_storage`, or generates and stores a new one.
+    pub fn load_or_generate(config_storage: &str) -> io::Result<Self> {
+        let path = Path::new(config_storage).join(IDENTITY_FILE_NAME);
+
+        if path.exists() {
+            let secret: [u8; 32] = match fs::read(&path)?.try_into() {
+                Ok(secret) => secret,
+                Err(_) => return Err(io::Error::new(InvalidData, "Stored identity key has an invalid length"))
+            };
+
+            return Ok(Self::from_secret(StaticSecret::from(secret)));
+        }
+
+        let identity = Self::generate();
+
+        fs::create_dir_all(config_storage)?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        // Only readable by the owner from the start, the key is never world readable
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        options.open(&path)?.write_all(&identity.secret.to_bytes())?;
+
+        return Ok(identity);
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        return self.public_key.to_bytes();
+    }
+
+    pub(crate) fn secret(&self) -> &StaticSecret {
+        return &self.secret;
+    }
+
+    pub fn fingerprint(&self) -> String {
+        return fingerprint(&self.public_key.to_bytes());
+    }
+}
+
+/// Human readable fingerprint of an identity public key, e.g. `3f2a 91bc 0d4e ...`.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let hash = Sha256::digest(public_key);
+
+    return hash[..16]
+        .chunks(2)
+        .map(|chunk| format!("{:02x}{:02x}", chunk[0], chunk[1]))
+        .collect::<Vec<String>>()
+        .join(" ");
+}
@@ -0,0 +1,45 @@
+use std::io::{self, Read};
+use protocol::communication::{HashAlgorithm, TransferTrailer};
+
+/// Hash over the content of all files of a transfer, in the order they are sent.
+/// Both sides hash the full content, including parts transferred during a previous attempt.
+#[derive(Default)]
+pub struct ContentHasher {
+    hasher: blake3::Hasher
+}
+
+impl ContentHasher {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Adds the next `length` bytes of `reader`. Fails if the reader ends early.
+    pub fn update_from_reader(&mut self, reader: &mut impl Read, length: u64) -> io::Result<()> {
+        let copied = io::copy(&mut reader.take(length), &mut self.hasher)?;
+
+        if copied < length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Content is shorter than expected"));
+        }
+
+        return Ok(());
+    }
+
+    pub fn trailer(&self) -> TransferTrailer {
+        return TransferTrailer {
+            algorithm: HashAlgorithm::Blake3 as i32,
+            hash: self.hasher.finalize().as_bytes().to_vec()
+        };
+    }
+
+    pub fn verify(&self, trailer: &TransferTrailer) -> bool {
+        if trailer.algorithm != HashAlgorithm::Blake3 as i32 {
+            return false;
+        }
+
+        return trailer.hash.as_slice() == self.hasher.finalize().as_bytes();
+    }
+}
@@ -6,15 +6,27 @@ use android_logger::Config;
 use log::LevelFilter;
 
 pub use protocol;
-pub use protocol::communication::ClipboardTransferIntent;
+pub use protocol::communication::{ClipboardRepresentation, ClipboardTransferIntent, DeclineReason};
 pub use protocol::discovery::Device;
 pub use protocol::DiscoveryDelegate;
 
+pub mod clipboard;
+pub mod content;
+pub mod device_registry;
 pub mod discovery;
 pub mod encryption;
+pub mod file_names;
+pub mod identity;
+pub mod integrity;
+pub mod mdns;
+pub mod negotiation;
+pub mod pairing;
+pub mod policy;
+pub mod resume;
 pub mod stream;
 pub mod nearby;
 pub mod transmission;
+pub mod udp;
 pub mod communication;
 pub mod connection_request;
 pub mod errors;
@@ -27,6 +39,15 @@ fn convert_os_str(os_str: &OsStr) -> Option<String> {
     os_str.to_str().map(|s| s.to_string())
 }
 
+/// Progress between 0 and 1. Nothing to transfer counts as complete.
+fn fraction(done: u64, total: u64) -> f64 {
+    if total == 0 {
+        return 1.0;
+    }
+
+    return done as f64 / total as f64;
+}
+
 #[cfg(target_os="android")]
 pub fn init_logger() {
     android_logger::init_once(
@@ -0,0 +1,194 @@
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use protocol::discovery::{Device, DeviceConnectionInfo, TcpConnectionInfo};
+use crate::errors::DiscoverySetupError;
+use crate::resume::to_hex;
+
+/// DNS-SD service type under which `NearbyServer` advertises itself on the local network.
+pub const MDNS_SERVICE_TYPE: &str = "_datarct._tcp.local.";
+
+/// How long dropping an `MdnsAdvertiser` waits for the goodbye to be sent.
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+const ID_PROPERTY: &str = "id";
+const NAME_PROPERTY: &str = "name";
+const DEVICE_TYPE_PROPERTY: &str = "type";
+const IDENTITY_KEY_PROPERTY: &str = "key";
+
+/// Describes the device as DNS-SD service. The device id is used as instance name,
+/// the TCP details as address and port, and the device itself is stored in TXT properties.
+/// BLE details are not advertised, as they are useless to a peer on the network.
+pub fn to_service_info(device_connection_info: &DeviceConnectionInfo) -> Option<ServiceInfo> {
+    let device = device_connection_info.device.as_ref()?;
+    let tcp = device_connection_info.tcp.as_ref()?;
+
+    let properties = [
+        (ID_PROPERTY, device.id.clone()),
+        (NAME_PROPERTY, device.name.clone()),
+        (DEVICE_TYPE_PROPERTY, device.device_type.to_string()),
+        (IDENTITY_KEY_PROPERTY, to_hex(&device_connection_info.identity_public_key))
+    ];
+
+    let host_name = format!("{}.local.", device.id);
+
+    return match ServiceInfo::new(MDNS_SERVICE_TYPE, &device.id, &host_name, tcp.hostname.as_str(), tcp.port as u16, &properties[..]) {
+        Ok(service_info) => Some(service_info),
+        Err(error) => {
+            println!("Unable to describe device as mDNS service: {:?}", error);
+            None
+        }
+    };
+}
+
+/// Reverse of `to_service_info`. IPv4 addresses are preferred, as the TCP hostname is used in "hostname:port".
+/// Services without a valid identity key are ignored.
+pub fn to_device_connection_info(service_info: &ServiceInfo) -> Option<DeviceConnectionInfo> {
+    let device = Device {
+        id: service_info.get_property_val_str(ID_PROPERTY)?.to_string(),
+        name: service_info.get_property_val_str(NAME_PROPERTY)?.to_string(),
+        device_type: service_info.get_property_val_str(DEVICE_TYPE_PROPERTY)?.parse().ok()?
+    };
+
+    let addresses = service_info.get_addresses();
+    let address = addresses.iter()
+        .find(|address| address.is_ipv4())
+        .or_else(|| addresses.iter().next())?;
+
+    let hostname = match address {
+        IpAddr::V4(address) => address.to_string(),
+        IpAddr::V6(address) => format!("[{}]", address)
+    };
+
+    // Every advertiser sends its identity key, a service without a valid one is not trusted
+    let identity_public_key = from_hex(service_info.get_property_val_str(IDENTITY_KEY_PROPERTY)?)
+        .filter(|identity_public_key| identity_public_key.len() == 32)?;
+
+    return Some(DeviceConnectionInfo {
+        device: Some(device),
+        tcp: Some(TcpConnectionInfo {
+            hostname,
+            port: service_info.get_port() as u32
+        }),
+        ble: None,
+        identity_public_key
+    });
+}
+
+/// Device id of a removed service, which is the instance name of its full name.
+pub fn device_id(fullname: &str) -> Option<String> {
+    return fullname.strip_suffix(MDNS_SERVICE_TYPE)
+        .and_then(|instance| instance.strip_suffix('.'))
+        .map(|instance| instance.to_string());
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    return hex.as_bytes()
+        .chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok()
+            .filter(|pair| pair.len() == 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect();
+}
+
+fn start_daemon() -> Result<ServiceDaemon, DiscoverySetupError> {
+    return ServiceDaemon::new().map_err(|error| {
+        println!("Unable to start mDNS daemon: {:?}", error);
+        DiscoverySetupError::UnableToSetupMdns
+    });
+}
+
+/// Announces a `NearbyServer` on the local network until it is dropped.
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    fullname: String
+}
+
+impl MdnsAdvertiser {
+    /// Fails if the device has no TCP details yet, or the service could not be registered.
+    pub fn start(device_connection_info: &DeviceConnectionInfo) -> Result<Self, DiscoverySetupError> {
+        let Some(service_info) = to_service_info(device_connection_info) else {
+            return Err(DiscoverySetupError::UnableToSetupMdns);
+        };
+
+        let daemon = start_daemon()?;
+        let fullname = service_info.get_fullname().to_string();
+
+        if let Err(error) = daemon.register(service_info) {
+            println!("Unable to register mDNS service: {:?}", error);
+            let _ = daemon.shutdown();
+
+            return Err(DiscoverySetupError::UnableToSetupMdns);
+        }
+
+        return Ok(Self {
+            daemon,
+            fullname
+        });
+    }
+}
+
+impl Drop for MdnsAdvertiser {
+    fn drop(&mut self) {
+        // Sends a goodbye, so peers remove the device right away
+        if let Ok(receiver) = self.daemon.unregister(&self.fullname) {
+            let _ = receiver.recv_timeout(UNREGISTER_TIMEOUT);
+        }
+
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Browses the local network for advertised `NearbyServer`s until it is dropped.
+pub struct MdnsBrowser {
+    daemon: ServiceDaemon
+}
+
+impl MdnsBrowser {
+    /// `device_found` is called for every resolved service, also when the details of a known device change.
+    /// `device_lost` is called with the device id, when a service is removed.
+    pub fn start<TFound, TLost>(device_found: TFound, device_lost: TLost) -> Result<Self, DiscoverySetupError>
+        where TFound: Fn(DeviceConnectionInfo) + Send + 'static, TLost: Fn(String) + Send + 'static {
+        let daemon = start_daemon()?;
+
+        let receiver = match daemon.browse(MDNS_SERVICE_TYPE) {
+            Ok(receiver) => receiver,
+            Err(error) => {
+                println!("Unable to browse for mDNS services: {:?}", error);
+                let _ = daemon.shutdown();
+
+                return Err(DiscoverySetupError::UnableToSetupMdns);
+            }
+        };
+
+        // Ends when the daemon shuts down and the channel is closed
+        thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                match event {
+                    ServiceEvent::ServiceResolved(service_info) => {
+                        if let Some(device_connection_info) = to_device_connection_info(&service_info) {
+                            device_found(device_connection_info);
+                        }
+                    },
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        if let Some(device_id) = device_id(&fullname) {
+                            device_lost(device_id);
+                        }
+                    },
+                    _ => {}
+                }
+            }
+        });
+
+        return Ok(Self {
+            daemon
+        });
+    }
+}
+
+impl Drop for MdnsBrowser {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
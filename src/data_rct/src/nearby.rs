@@ -1,29 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::{fs, thread};
-use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::net::ToSocketAddrs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
 
 use local_ip_address::local_ip;
 use prost_stream::Stream;
-use protocol::communication::{FileTransferIntent, TransferRequest, TransferRequestResponse};
+use protocol::communication::{Capability, ClipboardTransferIntent, DeclineReason, FileManifestEntry, FileTransferIntent, SenderIntroduction, TransferAck, TransferRequest, TransferRequestResponse};
 use protocol::communication::transfer_request::Intent;
 use protocol::discovery::{BluetoothLeConnectionInfo, Device, DeviceConnectionInfo, TcpConnectionInfo};
+use protocol::pairing::TrustedDevice;
 use tokio::sync::oneshot::{self, Sender};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::communication::{initiate_receiver_communication, initiate_sender_communication};
-use crate::connection_request::ConnectionRequest;
-use crate::{convert_os_str, init_logger};
-use crate::discovery::Discovery;
-use crate::encryption::{EncryptedReadWrite, EncryptedStream};
-use crate::errors::ConnectErrors;
-use crate::stream::NativeStreamDelegate;
-use crate::transmission::tcp::{TcpClient, TcpServer};
+use crate::communication::initiate_sender_communication;
+use crate::connection_request::{ConnectionRequest, IncomingConnectionHandler, DEFAULT_REQUEST_TIMEOUT};
+use crate::content::{ContentWrite, ContentWriter};
+use crate::{clipboard, convert_os_str, fraction, init_logger};
+use crate::clipboard::ClipboardItem;
+use crate::device_registry::DeviceRegistry;
+use crate::encryption::{EncryptedReadWrite, EncryptedStream, HandshakeDetails};
+use crate::errors::{ConnectErrors, IncomingErrors, PairingErrors};
+use crate::identity::DeviceIdentity;
+use crate::integrity::ContentHasher;
+use crate::mdns::MdnsAdvertiser;
+use crate::pairing::{TrustState, TrustStore};
+use crate::policy::{AutoAcceptPolicy, PolicyRule};
+use crate::resume;
+use crate::stream::{Close, NativeStreamDelegate, SharedNativeStream};
+use crate::transmission::tcp::{TcpClient, TcpServer, TcpShutdown};
+use crate::udp::UdpAnnouncer;
 
 pub trait BleServerImplementationDelegate: Send + Sync + Debug {
     fn start_server(&self);
@@ -34,6 +46,7 @@ pub trait L2CapDelegate: Send + Sync + Debug {
     fn open_l2cap_connection(&self, connection_id: String, peripheral_uuid: String, psm: u32);
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ConnectionIntentType {
     FileTransfer,
     Clipboard
@@ -46,13 +59,23 @@ pub enum ConnectionMedium {
 
 pub enum SendProgressState {
     Unknown,
+    /// First state of every send. Pass the id to `cancel_send` to cancel the transfer.
+    Started { send_id: String },
     Connecting,
     Requesting,
     ConnectionMediumUpdate { medium: ConnectionMedium },
     Transferring { progress: f64 },
     Cancelled,
+    /// The receiver cancelled the transfer while it was receiving the content.
+    CancelledByReceiver,
     Finished,
-    Declined
+    Declined,
+    /// Nobody answered the request on the receiver in time.
+    RequestExpired,
+    /// The receiver declined for a reason other than a plain rejection by the user, or added a message.
+    DeclinedWithReason { reason: DeclineReason, message: Option<String> },
+    /// The receiver is not paired yet. The transfer continues once `confirm_verification_code` is called with the id of `Started`.
+    VerificationRequired { verification_code: String }
 }
 
 pub trait SendProgressDelegate: Send + Sync + Debug {
@@ -60,18 +83,37 @@ pub trait SendProgressDelegate: Send + Sync + Debug {
 }
 
 pub trait NearbyConnectionDelegate: Send + Sync + Debug {
+    /// An unpaired sender connected. The code has to be shown right away, so the user of the sender can compare it.
+    /// The request of the sender follows once that user confirmed the code.
+    fn received_verification_code(&self, sender: Device, verification_code: String);
+    /// The sender of `received_verification_code` disconnected without a request, e.g. because its user rejected the code.
+    fn verification_aborted(&self, sender: Device);
     fn received_connection_request(&self, request: Arc<ConnectionRequest>);
+    /// A request was declined without asking the user, e.g. because of its size, an auto accept rule or unsafe paths.
+    fn declined_connection_request(&self, sender: Option<Device>, reason: DeclineReason);
 }
 
 pub struct NearbyServerLockedVariables {
     pub device_connection_info: DeviceConnectionInfo,
+    device_registry: Arc<std::sync::Mutex<Option<Arc<DeviceRegistry>>>>,
     tcp_server: Option<TcpServer>,
+    mdns_advertiser: Option<MdnsAdvertiser>,
+    udp_announcer: Option<UdpAnnouncer>,
     ble_server_implementation: Option<Box<dyn BleServerImplementationDelegate>>,
     ble_l2_cap_client: Option<Box<dyn L2CapDelegate>>,
     nearby_connection_delegate: Option<Arc<std::sync::Mutex<Box<dyn NearbyConnectionDelegate>>>>,
     pub advertise: bool,
     file_storage: String,
-    l2cap_connections: HashMap<String, Sender<Box<dyn NativeStreamDelegate>>>
+    identity: Arc<DeviceIdentity>,
+    trust_store: Arc<std::sync::Mutex<TrustStore>>,
+    l2cap_connections: HashMap<String, Sender<Box<dyn NativeStreamDelegate>>>,
+    pending_verifications: HashMap<String, Sender<bool>>,
+    active_sends: HashMap<String, ActiveSend>,
+    pending_requests: Arc<std::sync::Mutex<Vec<Arc<ConnectionRequest>>>>,
+    request_timeout: Arc<AtomicU64>,
+    max_transfer_size: Arc<AtomicU64>,
+    auto_accept_policy: Arc<std::sync::Mutex<AutoAcceptPolicy>>,
+    active_transfers: Arc<std::sync::Mutex<HashSet<String>>>
 }
 
 pub struct NearbyServer {
@@ -79,13 +121,31 @@ pub struct NearbyServer {
 }
 
 impl NearbyServer {
-    pub fn new(my_device: Device, file_storage: String, delegate: Option<Box<dyn NearbyConnectionDelegate>>) -> Self {
+    /// `config_storage` is a private directory used to persist the identity of this device.
+    pub fn new(my_device: Device, file_storage: String, config_storage: String, delegate: Option<Box<dyn NearbyConnectionDelegate>>) -> Self {
         init_logger();
 
+        let identity = match DeviceIdentity::load_or_generate(&config_storage) {
+            Ok(identity) => identity,
+            Err(error) => {
+                println!("Unable to load device identity, using a temporary one: {:?}", error);
+                DeviceIdentity::generate()
+            }
+        };
+
+        let trust_store = match TrustStore::load(&config_storage) {
+            Ok(trust_store) => trust_store,
+            Err(error) => {
+                println!("Unable to load trusted devices, pairings will not be persisted: {:?}", error);
+                TrustStore::in_memory()
+            }
+        };
+
         let device_connection_info = DeviceConnectionInfo {
             device: Some(my_device.clone()),
             ble: None,
-            tcp: None
+            tcp: None,
+            identity_public_key: identity.public_key().to_vec()
         };
         let nearby_connection_delegate = match delegate {
             Some(d) => Some(Arc::new(std::sync::Mutex::new(d))),
@@ -95,13 +155,25 @@ impl NearbyServer {
         return Self {
             variables: Arc::new(RwLock::new(NearbyServerLockedVariables {
                 device_connection_info,
+                device_registry: Arc::new(std::sync::Mutex::new(None)),
                 tcp_server: None,
+                mdns_advertiser: None,
+                udp_announcer: None,
                 ble_server_implementation: None,
                 ble_l2_cap_client: None,
                 nearby_connection_delegate,
                 advertise: false,
                 file_storage,
-                l2cap_connections: HashMap::new()
+                identity: Arc::new(identity),
+                trust_store: Arc::new(std::sync::Mutex::new(trust_store)),
+                l2cap_connections: HashMap::new(),
+                pending_verifications: HashMap::new(),
+                active_sends: HashMap::new(),
+                pending_requests: Arc::new(std::sync::Mutex::new(vec![])),
+                request_timeout: Arc::new(AtomicU64::new(DEFAULT_REQUEST_TIMEOUT.as_secs())),
+                max_transfer_size: Arc::new(AtomicU64::new(0)),
+                auto_accept_policy: Arc::new(std::sync::Mutex::new(AutoAcceptPolicy::default())),
+                active_transfers: Arc::new(std::sync::Mutex::new(HashSet::new()))
             }))
         };
     }
@@ -114,12 +186,102 @@ impl NearbyServer {
         self.variables.blocking_write().ble_server_implementation = Some(implementation)
     }
 
+    /// Devices can only be sent to, once they are in this registry. Usually the one of `Discovery::get_device_registry`.
+    /// Sending fails with `ConnectErrors::NoDeviceRegistry` until it is set.
+    pub fn set_device_registry(&self, device_registry: Arc<DeviceRegistry>) {
+        let shared_device_registry = self.variables.blocking_read().device_registry.clone();
+        *shared_device_registry.lock().expect("Failed to lock device registry") = Some(device_registry);
+    }
+
+    pub fn get_identity_fingerprint(&self) -> String {
+        return self.variables.blocking_read().identity.fingerprint();
+    }
+
+    /// Incoming requests, that were neither accepted, declined nor expired yet.
+    pub fn get_pending_requests(&self) -> Vec<Arc<ConnectionRequest>> {
+        let pending_requests = self.variables.blocking_read().pending_requests.clone();
+        let mut pending_requests = pending_requests.lock().expect("Failed to lock pending requests");
+        pending_requests.retain(|pending_request| pending_request.is_pending());
+
+        return pending_requests.clone();
+    }
+
+    /// Declines all pending requests, e.g. when the app goes to the background.
+    pub fn decline_pending_requests(&self) {
+        for pending_request in self.get_pending_requests() {
+            pending_request.decline();
+        }
+    }
+
+    /// Unanswered incoming requests are declined after `seconds`, and verification codes of sends,
+    /// that were not confirmed in time, count as rejected. Zero disables the timeout.
+    pub fn set_request_timeout(&self, seconds: u64) {
+        self.variables.blocking_read().request_timeout.store(seconds, Ordering::Relaxed);
+    }
+
+    /// File and clipboard transfers larger than `bytes` are declined without asking. Zero removes the limit.
+    pub fn set_max_transfer_size(&self, bytes: u64) {
+        self.variables.blocking_read().max_transfer_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Replaces the rules, that accept or decline incoming requests without asking the user.
+    pub fn set_auto_accept_rules(&self, rules: Vec<PolicyRule>) {
+        let auto_accept_policy = self.variables.blocking_read().auto_accept_policy.clone();
+        *auto_accept_policy.lock().expect("Failed to lock auto accept policy") = AutoAcceptPolicy::new(rules);
+    }
+
+    /// Devices the user paired with.
+    pub fn get_trusted_devices(&self) -> Vec<TrustedDevice> {
+        let trust_store = self.variables.blocking_read().trust_store.clone();
+        let devices = trust_store.lock().expect("Failed to lock trust store").get_devices();
+
+        return devices;
+    }
+
+    /// Marks the device as paired, pinning the given identity key.
+    pub fn pair_device(&self, device: Device, identity_public_key: Vec<u8>) -> Result<(), PairingErrors> {
+        if identity_public_key.len() != 32 {
+            return Err(PairingErrors::InvalidIdentityKey);
+        }
+
+        let trust_store = self.variables.blocking_read().trust_store.clone();
+        let result = trust_store.lock().expect("Failed to lock trust store").pair(&device, &identity_public_key);
+
+        return match result {
+            Ok(_) => Ok(()),
+            Err(error) => Err(PairingErrors::FailedToPersist { error: error.to_string() })
+        };
+    }
+
+    pub fn forget_device(&self, device_id: String) -> Result<(), PairingErrors> {
+        let trust_store = self.variables.blocking_read().trust_store.clone();
+        let result = trust_store.lock().expect("Failed to lock trust store").forget(&device_id);
+
+        return match result {
+            Ok(_) => Ok(()),
+            Err(error) => Err(PairingErrors::FailedToPersist { error: error.to_string() })
+        };
+    }
+
+    /// Confirms (or rejects) the verification code shown for the outgoing transfer with the id reported by `SendProgressState::Started`.
+    pub fn confirm_verification_code(&self, send_id: String, confirmed: bool) {
+        let sender = self.variables.blocking_write().pending_verifications.remove(&send_id);
+
+        if let Some(sender) = sender {
+            let _ = sender.send(confirmed);
+        }
+    }
+
     pub fn change_device(&self, new_device: Device) {
-        self.variables.blocking_write().device_connection_info.device = Some(new_device);
+        let mut variables = self.variables.blocking_write();
+        variables.device_connection_info.device = Some(new_device);
+        NearbyServer::refresh_network_advertisements(&mut variables);
     }
 
     pub fn set_bluetooth_le_details(&self, ble_info: BluetoothLeConnectionInfo) {
-        self.variables.blocking_write().device_connection_info.ble = Some(ble_info)
+        let mut variables = self.variables.blocking_write();
+        variables.device_connection_info.ble = Some(ble_info);
+        NearbyServer::refresh_network_advertisements(&mut variables);
     }
 
     pub fn set_tcp_details(&self, tcp_info: TcpConnectionInfo) {
@@ -139,6 +301,8 @@ impl NearbyServer {
     }
 
     pub async fn start(&self) {
+        resume::remove_stale_partial_files(&self.variables.read().await.file_storage);
+
         if self.variables.read().await.tcp_server.is_none() {
             let delegate = self.variables.read().await.nearby_connection_delegate.clone();
 
@@ -146,16 +310,12 @@ impl NearbyServer {
                 return;
             };
 
-            let file_storage = self.variables.read().await.file_storage.clone();
-            let tcp_server = TcpServer::new(delegate, file_storage).await;
+            let tcp_server = TcpServer::new(self.incoming_connection_handler(delegate).await).await;
 
             if let Ok(tcp_server) = tcp_server {
                 let ip = self.get_current_ip();
 
                 if let Some(my_local_ip) = ip {
-                    println!("IP: {:?}", my_local_ip);
-                    println!("Port: {:?}", tcp_server.port);
-
                     tcp_server.start_loop();
 
                     self.set_tcp_details(TcpConnectionInfo {
@@ -172,21 +332,104 @@ impl NearbyServer {
 
         self.variables.write().await.advertise = true;
 
+        if self.variables.read().await.mdns_advertiser.is_none() {
+            let mdns_advertiser = NearbyServer::start_mdns_advertiser(&self.variables.read().await.device_connection_info);
+            self.variables.write().await.mdns_advertiser = mdns_advertiser;
+        }
+
+        if self.variables.read().await.udp_announcer.is_none() {
+            let udp_announcer = NearbyServer::start_udp_announcer(&self.variables.read().await.device_connection_info);
+            self.variables.write().await.udp_announcer = udp_announcer;
+        }
+
         if let Some(ble_advertisement_implementation) = &self.variables.read().await.ble_server_implementation {
             ble_advertisement_implementation.start_server();
         };
     }
 
+    /// Restarts running advertisements, so they carry the changed connection info.
+    fn refresh_network_advertisements(variables: &mut NearbyServerLockedVariables) {
+        if variables.mdns_advertiser.is_some() {
+            // Dropping the previous advertiser first, as both would use the same instance name
+            variables.mdns_advertiser = None;
+            variables.mdns_advertiser = NearbyServer::start_mdns_advertiser(&variables.device_connection_info);
+        }
+
+        if variables.udp_announcer.is_some() {
+            variables.udp_announcer = None;
+            variables.udp_announcer = NearbyServer::start_udp_announcer(&variables.device_connection_info);
+        }
+    }
+
+    /// Announces the device on the local network, so `Discovery` finds it without BLE.
+    fn start_mdns_advertiser(device_connection_info: &DeviceConnectionInfo) -> Option<MdnsAdvertiser> {
+        // Without a TCP server, there is nothing to connect to
+        device_connection_info.tcp.as_ref()?;
+
+        return match MdnsAdvertiser::start(device_connection_info) {
+            Ok(mdns_advertiser) => Some(mdns_advertiser),
+            Err(error) => {
+                println!("Unable to advertise via mDNS: {:?}", error);
+                None
+            }
+        };
+    }
+
+    /// Announces the device on the UDP multicast group, for networks that filter mDNS.
+    fn start_udp_announcer(device_connection_info: &DeviceConnectionInfo) -> Option<UdpAnnouncer> {
+        device_connection_info.tcp.as_ref()?;
+
+        return match UdpAnnouncer::start(device_connection_info) {
+            Ok(udp_announcer) => Some(udp_announcer),
+            Err(error) => {
+                println!("Unable to announce via UDP: {:?}", error);
+                None
+            }
+        };
+    }
+
     pub async fn restart_server(&self) {
         self.stop();
         self.start().await;
     }
 
-    async fn initiate_sender<T>(&self, raw_stream: T) -> Result<EncryptedStream<T>, ConnectErrors> where T: Read + Write {
-        return Ok(match initiate_sender_communication(raw_stream).await {
+    async fn incoming_connection_handler(&self, delegate: Arc<std::sync::Mutex<Box<dyn NearbyConnectionDelegate>>>) -> IncomingConnectionHandler {
+        return IncomingConnectionHandler {
+            delegate,
+            file_storage: self.variables.read().await.file_storage.clone(),
+            identity: self.variables.read().await.identity.clone(),
+            trust_store: self.variables.read().await.trust_store.clone(),
+            pending_requests: self.variables.read().await.pending_requests.clone(),
+            request_timeout: self.variables.read().await.request_timeout.clone(),
+            max_transfer_size: self.variables.read().await.max_transfer_size.clone(),
+            auto_accept_policy: self.variables.read().await.auto_accept_policy.clone(),
+            device_registry: self.variables.read().await.device_registry.clone(),
+            active_transfers: self.variables.read().await.active_transfers.clone()
+        };
+    }
+
+    async fn initiate_sender<T>(&self, raw_stream: T, receiver: &Device, device_registry: &DeviceRegistry) -> Result<EncryptedStream<T>, ConnectErrors> where T: Read + Write {
+        let identity = self.variables.read().await.identity.clone();
+
+        let encrypted_stream = match initiate_sender_communication(raw_stream, &identity).await {
             Ok(stream) => stream,
-            Err(error) => return Err(ConnectErrors::FailedToEncryptStream { error: error.to_string() })
-        });
+            Err(error) => {
+                if let Some(IncomingErrors::InvalidVersion) = error.downcast_ref::<IncomingErrors>() {
+                    return Err(ConnectErrors::IncompatibleProtocolVersion);
+                }
+
+                return Err(ConnectErrors::FailedToEncryptStream { error: error.to_string() });
+            }
+        };
+
+        // The receiver has to prove it holds the identity key it advertised
+        if let Some(handshake_details) = &encrypted_stream.handshake_details {
+            if device_registry.matches_identity(&receiver.id, &handshake_details.peer_identity_public_key) != Some(true) {
+                return Err(ConnectErrors::InvalidReceiverIdentity);
+            }
+        }
+
+        return Ok(encrypted_stream);
     }
 
     pub fn handle_incoming_ble_connection(&self, connection_id: String, native_stream: Box<dyn NativeStreamDelegate>) {
@@ -197,7 +440,7 @@ impl NearbyServer {
         }
     }
 
-    async fn connect_tcp(&self, connection_details: &DeviceConnectionInfo) -> Result<Box<dyn EncryptedReadWrite>, ConnectErrors> {
+    async fn connect_tcp(&self, receiver: &Device, connection_details: &DeviceConnectionInfo, device_registry: &DeviceRegistry, active_send: &ActiveSend) -> Result<Box<dyn EncryptedReadWrite>, ConnectErrors> {
         let Some(tcp_connection_details) = &connection_details.tcp else {
             return Err(ConnectErrors::FailedToGetTcpDetails);
         };
@@ -218,7 +461,11 @@ impl NearbyServer {
         let tcp_stream = TcpClient::connect(socket_address);
 
         if let Ok(raw_stream) = tcp_stream {
-            let encrypted_stream = self.initiate_sender(raw_stream).await?;
+            if let Ok(shutdown_stream) = raw_stream.try_clone() {
+                active_send.set_connection(Box::new(TcpShutdown(shutdown_stream)));
+            }
+
+            let encrypted_stream = self.initiate_sender(raw_stream, receiver, device_registry).await?;
             return Ok(Box::new(encrypted_stream));
         }
 
@@ -226,12 +473,20 @@ impl NearbyServer {
         return Err(ConnectErrors::FailedToOpenTcpStream);
     }
 
-    async fn connect(&self, device: Device, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<Box<dyn EncryptedReadWrite>, ConnectErrors> {
-        let Some(connection_details) = Discovery::get_connection_details(device) else {
+    async fn connect(&self, device: Device, active_send: &ActiveSend, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<Box<dyn EncryptedReadWrite>, ConnectErrors> {
+        let device_registry = self.variables.read().await.device_registry.lock().expect("Failed to lock device registry").clone();
+
+        let Some(device_registry) = device_registry else {
+            return Err(ConnectErrors::NoDeviceRegistry);
+        };
+
+        let connection_details = device_registry.get_connection_details(&device.id);
+
+        let Some(connection_details) = connection_details else {
             return Err(ConnectErrors::FailedToGetConnectionDetails);
         };
 
-        let encrypted_stream = self.connect_tcp(&connection_details).await;
+        let encrypted_stream = self.connect_tcp(&device, &connection_details, &device_registry, active_send).await;
 
         if let Ok(encrypted_stream) = encrypted_stream {
             NearbyServer::update_progress(&progress_delegate, SendProgressState::ConnectionMediumUpdate { medium: ConnectionMedium::WiFi });
@@ -265,12 +520,77 @@ impl NearbyServer {
             return Err(ConnectErrors::FailedToEstablishBleConnection);
         };
 
-        let encrypted_stream = self.initiate_sender(connection).await?;
+        let connection: Arc<dyn NativeStreamDelegate> = Arc::from(connection);
+        active_send.set_connection(Box::new(SharedNativeStream(connection.clone())));
+
+        let encrypted_stream = self.initiate_sender(SharedNativeStream(connection), &device, &device_registry).await?;
         NearbyServer::update_progress(&progress_delegate, SendProgressState::ConnectionMediumUpdate { medium: ConnectionMedium::BLE });
 
         return Ok(Box::new(encrypted_stream));
     }
 
+    /// Lets the user compare the verification code, unless the receiver is already paired.
+    /// A confirmed code pairs the receiver. Codes, that are not confirmed within the request timeout, are rejected.
+    async fn verify_receiver(&self, active_send: &ActiveSend, receiver: &Device, handshake_details: Option<HandshakeDetails>, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let Some(handshake_details) = handshake_details else {
+            return Err(ConnectErrors::VerificationRejected);
+        };
+
+        let trust_store = self.variables.read().await.trust_store.clone();
+
+        let trust_state = trust_store.lock().expect("Failed to lock trust store").check_device(receiver, &handshake_details.peer_identity_public_key);
+
+        if trust_state == TrustState::Paired {
+            return Ok(());
+        }
+
+        let (sender, confirmation) = oneshot::channel::<bool>();
+        self.variables.write().await.pending_verifications.insert(active_send.id.clone(), sender);
+
+        // `cancel_send` sets the flag before it looks for the verification
+        if active_send.cancelled.load(Ordering::Relaxed) {
+            self.variables.write().await.pending_verifications.remove(&active_send.id);
+            return Err(ConnectErrors::Cancelled);
+        }
+
+        NearbyServer::update_progress(progress_delegate, SendProgressState::VerificationRequired {
+            verification_code: handshake_details.verification_code.clone()
+        });
+
+        let request_timeout = Duration::from_secs(self.variables.read().await.request_timeout.load(Ordering::Relaxed));
+
+        if !request_timeout.is_zero() {
+            let variables = self.variables.clone();
+            let send_id = active_send.id.clone();
+
+            thread::spawn(move || {
+                thread::sleep(request_timeout);
+
+                if let Some(sender) = variables.blocking_write().pending_verifications.remove(&send_id) {
+                    println!("Verification code was not confirmed in time");
+                    let _ = sender.send(false);
+                }
+            });
+        }
+
+        let confirmed = confirmation.await;
+
+        // The wait may have ended without `confirm_verification_code`
+        self.variables.write().await.pending_verifications.remove(&active_send.id);
+
+        let Ok(true) = confirmed else {
+            return Err(ConnectErrors::VerificationRejected);
+        };
+
+        let result = trust_store.lock().expect("Failed to lock trust store").pair(receiver, &handshake_details.peer_identity_public_key);
+
+        if let Err(error) = result {
+            println!("Unable to pair device: {:?}", error);
+        }
+
+        return Ok(());
+    }
+
     fn update_progress(progress_delegate: &Option<Box<dyn SendProgressDelegate>>, state: SendProgressState) {
         if let Some(progress_delegate) = progress_delegate {
             progress_delegate.progress_changed(state);
@@ -278,82 +598,444 @@ impl NearbyServer {
     }
 
     pub async fn send_file(&self, receiver: Device, file_path: String, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        return self.send_files(receiver, vec![file_path], progress_delegate).await;
+    }
+
+    /// Sends all files as a single batch, which the receiver accepts or declines at once.
+    pub async fn send_files(&self, receiver: Device, file_paths: Vec<String>, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let mut files = Vec::with_capacity(file_paths.len());
+
+        for file_path in &file_paths {
+            let metadata = match fs::metadata(file_path) {
+                Ok(metadata) => metadata,
+                Err(error) => return Err(ConnectErrors::FailedToDetermineFileSize { error: error.to_string() })
+            };
+
+            let file_name = Path::new(file_path).file_name().and_then(convert_os_str);
+
+            let Some(file_name) = file_name else {
+                return Err(ConnectErrors::FailedToDetermineFileSize { error: format!("Invalid file path: {}", file_path) });
+            };
+
+            files.push(SourceFile {
+                path: PathBuf::from(file_path),
+                manifest_entry: FileManifestEntry {
+                    file_name,
+                    file_size: metadata.len(),
+                    modified: resume::modification_time(&metadata)
+                }
+            });
+        }
+
+        return self.send_batch(receiver, files, None, vec![], progress_delegate).await;
+    }
+
+    /// Sends the directory including all subdirectories. The receiver recreates the tree, including empty directories.
+    pub async fn send_directory(&self, receiver: Device, directory_path: String, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let root = Path::new(&directory_path);
+
+        let Some(directory_name) = root.file_name().and_then(convert_os_str) else {
+            return Err(ConnectErrors::FailedToReadDirectory { error: format!("Invalid directory path: {}", directory_path) });
+        };
+
+        let mut files = vec![];
+        let mut directories = vec![directory_name.clone()];
+
+        if let Err(error) = collect_directory(root, &directory_name, &mut files, &mut directories) {
+            return Err(ConnectErrors::FailedToReadDirectory { error: error.to_string() });
+        }
+
+        return self.send_batch(receiver, files, Some(directory_name), directories, progress_delegate).await;
+    }
+
+    /// Sends all representations of the clipboard content, most preferred first.
+    /// The request only describes the representations, their data is sent once the receiver accepted.
+    pub async fn send_clipboard(&self, receiver: Device, items: Vec<ClipboardItem>, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let active_send = self.register_send(&progress_delegate).await;
+        let result = self.transfer_clipboard(receiver, items, &active_send, progress_delegate).await;
+        self.variables.write().await.active_sends.remove(&active_send.id);
+
+        return result;
+    }
+
+    async fn transfer_clipboard(&self, receiver: Device, items: Vec<ClipboardItem>, active_send: &ActiveSend, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let clipboard_transfer_intent = ClipboardTransferIntent {
+            representations: items.iter().map(|item| item.representation()).collect()
+        };
+
+        if let Err(error) = clipboard::validate_clipboard(&clipboard_transfer_intent) {
+            return Err(ConnectErrors::InvalidClipboardContent { error });
+        }
+
+        let total_size = clipboard::clipboard_size(&clipboard_transfer_intent);
+
         NearbyServer::update_progress(&progress_delegate, SendProgressState::Connecting);
 
-        let mut encrypted_stream = match self.connect(receiver, &progress_delegate).await {
-            Ok(connection) => connection,
-            Err(error) => return Err(error)
+        let mut encrypted_stream = self.connect(receiver.clone(), active_send, &progress_delegate).await?;
+
+        let supports_clipboard = encrypted_stream.handshake_details()
+            .is_some_and(|handshake_details| handshake_details.negotiated_protocol.supports(Capability::Clipboard));
+
+        if !supports_clipboard {
+            let _ = encrypted_stream.finish();
+            encrypted_stream.close();
+
+            return Err(ConnectErrors::UnsupportedByReceiver);
+        }
+
+        self.request_transfer(active_send, &receiver, &mut encrypted_stream, Intent::Clipboard(clipboard_transfer_intent), &progress_delegate).await?;
+
+        NearbyServer::update_progress(&progress_delegate, SendProgressState::Transferring { progress: 0.0 });
+
+        let mut content_hasher = ContentHasher::new();
+        let mut all_written: u64 = 0;
+        let mut cancelled_by_receiver = false;
+        let mut content_writer = ContentWriter::new(&mut encrypted_stream);
+
+        'items: for item in &items {
+            for chunk in item.data.chunks(1024) {
+                if active_send.cancelled.load(Ordering::Relaxed) {
+                    let _ = content_writer.cancel();
+                    break 'items;
+                }
+
+                match content_writer.write_all(chunk) {
+                    Ok(ContentWrite::Written) => {},
+                    Ok(ContentWrite::CancelledByReceiver) => {
+                        cancelled_by_receiver = true;
+                        break 'items;
+                    },
+                    Err(_) => break 'items
+                }
+
+                content_hasher.update(chunk);
+                all_written += chunk.len() as u64;
+
+                NearbyServer::update_progress(&progress_delegate, SendProgressState::Transferring { progress: fraction(all_written, total_size) });
+            }
+        }
+
+        if all_written < total_size || !NearbyServer::finish_content(&mut content_writer, &mut cancelled_by_receiver) {
+            return NearbyServer::abort_transfer(&mut encrypted_stream, cancelled_by_receiver, &progress_delegate);
+        }
+
+        return NearbyServer::finish_transfer(&mut encrypted_stream, &content_hasher, &progress_delegate);
+    }
+
+    /// Makes the send cancellable through `cancel_send`, and tells the app its id.
+    async fn register_send(&self, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> ActiveSend {
+        let active_send = ActiveSend {
+            id: Uuid::new_v4().to_string(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            connection: Arc::new(std::sync::Mutex::new(None))
         };
 
-        let mut proto_stream = Stream::new(&mut encrypted_stream);
+        self.variables.write().await.active_sends.insert(active_send.id.clone(), active_send.clone());
+        NearbyServer::update_progress(progress_delegate, SendProgressState::Started { send_id: active_send.id.clone() });
+
+        return active_send;
+    }
+
+    /// Stops the transfer with the id reported by `SendProgressState::Started`.
+    /// While the content is sent, the receiver is told about the cancellation, so it can discard what it received so far.
+    /// Before that, the connection is closed, which also ends waiting for the verification code or the answer of the receiver.
+    pub fn cancel_send(&self, send_id: String) {
+        let Some(active_send) = self.variables.blocking_read().active_sends.get(&send_id).cloned() else {
+            return;
+        };
+
+        active_send.cancelled.store(true, Ordering::Relaxed);
+
+        let verification = self.variables.blocking_write().pending_verifications.remove(&send_id);
+
+        if let Some(verification) = verification {
+            let _ = verification.send(false);
+        }
+
+        active_send.close_connection();
+    }
+
+    /// Waits until the receiver is verified, then sends the transfer request and waits until the receiver has accepted it.
+    /// Nothing about the transfer is sent to a receiver, whose verification code was not confirmed.
+    async fn request_transfer(&self, active_send: &ActiveSend, receiver: &Device, encrypted_stream: &mut Box<dyn EncryptedReadWrite>, intent: Intent, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<TransferRequestResponse, ConnectErrors> {
+        let response = self.wait_for_acceptance(active_send, receiver, encrypted_stream, intent, progress_delegate).await;
+
+        // From here on, the content loop handles cancellations and tells the receiver
+        active_send.connection.lock().expect("Failed to lock connection").take();
 
-        let path = Path::new(&file_path);
-        let filename = path.file_name().expect("Failed to get file name");
-        let metadata = fs::metadata(&file_path).expect("Failed to get metadata for file");
-        let file_size = metadata.len();
+        if response.is_err() && active_send.cancelled.load(Ordering::Relaxed) {
+            let _ = encrypted_stream.finish();
+            encrypted_stream.close();
 
-        NearbyServer::update_progress(&progress_delegate, SendProgressState::Requesting);
+            NearbyServer::update_progress(progress_delegate, SendProgressState::Cancelled);
+
+            return Err(ConnectErrors::Cancelled);
+        }
+
+        return response;
+    }
+
+    async fn wait_for_acceptance(&self, active_send: &ActiveSend, receiver: &Device, encrypted_stream: &mut Box<dyn EncryptedReadWrite>, intent: Intent, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<TransferRequestResponse, ConnectErrors> {
+        let sender_introduction = SenderIntroduction {
+            device: self.variables.read().await.device_connection_info.device.clone()
+        };
+
+        // The receiver shows its code now, while the user compares it
+        let mut proto_stream = Stream::new(&mut *encrypted_stream);
+        let _ = proto_stream.send(&sender_introduction);
+
+        if let Err(error) = self.verify_receiver(active_send, receiver, encrypted_stream.handshake_details(), progress_delegate).await {
+            let _ = encrypted_stream.finish();
+            encrypted_stream.close();
+
+            return Err(error);
+        }
+
+        NearbyServer::update_progress(progress_delegate, SendProgressState::Requesting);
 
         let transfer_request = TransferRequest {
             device: self.variables.read().await.device_connection_info.device.clone(),
-            intent: Some(Intent::FileTransfer(FileTransferIntent {
-                file_name: convert_os_str(filename),
-                file_size,
-                multiple: false
-            }))
+            intent: Some(intent)
         };
 
+        let mut proto_stream = Stream::new(&mut *encrypted_stream);
         let _ = proto_stream.send(&transfer_request);
 
+        let mut proto_stream = Stream::new(&mut *encrypted_stream);
         let response = match proto_stream.recv::<TransferRequestResponse>() {
             Ok(message) => message,
             Err(error) => return Err(ConnectErrors::FailedToGetTransferRequestResponse { error: error.to_string() })
         };
 
         if !response.accepted {
-            NearbyServer::update_progress(&progress_delegate, SendProgressState::Declined);
-            return Err(ConnectErrors::Declined);
+            let _ = encrypted_stream.finish();
+            encrypted_stream.close();
+
+            let reason = response.decline_reason();
+
+            let progress = match (reason, &response.decline_message) {
+                (DeclineReason::Unspecified | DeclineReason::DeclinedByUser, None) => SendProgressState::Declined,
+                (DeclineReason::RequestExpired, _) => SendProgressState::RequestExpired,
+                _ => SendProgressState::DeclinedWithReason { reason, message: response.decline_message.clone() }
+            };
+
+            NearbyServer::update_progress(progress_delegate, progress);
+
+            return Err(match reason {
+                DeclineReason::Unspecified | DeclineReason::DeclinedByUser => ConnectErrors::Declined,
+                DeclineReason::RequestExpired => ConnectErrors::RequestExpired,
+                DeclineReason::FileAlreadyExists => ConnectErrors::FileAlreadyExists,
+                DeclineReason::TransferTooLarge => ConnectErrors::TransferTooLarge,
+                DeclineReason::InsufficientStorage => ConnectErrors::InsufficientStorage,
+                DeclineReason::ReceiverBusy => ConnectErrors::ReceiverBusy,
+                DeclineReason::UntrustedSender => ConnectErrors::UntrustedSender,
+                DeclineReason::UnsupportedIntent => ConnectErrors::UnsupportedIntent,
+                DeclineReason::DeclinedByPolicy => ConnectErrors::DeclinedByPolicy,
+                DeclineReason::InvalidRequest => ConnectErrors::InvalidRequest
+            });
+        }
+
+        return Ok(response);
+    }
+
+    async fn send_batch(&self, receiver: Device, files: Vec<SourceFile>, directory_name: Option<String>, directories: Vec<String>, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let active_send = self.register_send(&progress_delegate).await;
+        let result = self.transfer_batch(receiver, files, directory_name, directories, &active_send, progress_delegate).await;
+        self.variables.write().await.active_sends.remove(&active_send.id);
+
+        return result;
+    }
+
+    async fn transfer_batch(&self, receiver: Device, files: Vec<SourceFile>, directory_name: Option<String>, directories: Vec<String>, active_send: &ActiveSend, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let file_size: u64 = files.iter().map(|file| file.manifest_entry.file_size).sum();
+        let source_paths: Vec<PathBuf> = files.iter().map(|file| file.path.clone()).collect();
+        let transfer_id = resume::transfer_id(&receiver.id, &source_paths);
+
+        NearbyServer::update_progress(&progress_delegate, SendProgressState::Connecting);
+
+        let mut encrypted_stream = match self.connect(receiver.clone(), active_send, &progress_delegate).await {
+            Ok(connection) => connection,
+            Err(error) => return Err(error)
+        };
+
+        let handshake_details = encrypted_stream.handshake_details();
+        let supports = |capability: Capability| handshake_details.as_ref()
+            .is_some_and(|handshake_details| handshake_details.negotiated_protocol.supports(capability));
+
+        let is_directory = directory_name.is_some();
+
+        if (files.len() > 1 && !supports(Capability::MultiFile)) || (is_directory && !supports(Capability::Directories)) {
+            let _ = encrypted_stream.finish();
+            encrypted_stream.close();
+
+            return Err(ConnectErrors::UnsupportedByReceiver);
         }
 
-        let mut file = OpenOptions::new()
-            .write(false)
-            .create(false)
-            .read(true)
-            .open(file_path.clone())
-            .expect("Failed to open file");
+        let intent = Intent::FileTransfer(FileTransferIntent {
+            file_name: match directory_name {
+                Some(directory_name) => Some(directory_name),
+                None if files.len() == 1 => Some(files[0].manifest_entry.file_name.clone()),
+                None => None
+            },
+            file_size,
+            multiple: files.len() > 1 || is_directory,
+            files: files.iter().map(|file| file.manifest_entry.clone()).collect(),
+            directories,
+            file_count: files.len() as u32,
+            transfer_id
+        });
+
+        let response = self.request_transfer(active_send, &receiver, &mut encrypted_stream, intent, &progress_delegate).await?;
+
+        let resume_offset = if supports(Capability::Resumption) { response.resume_offset } else { 0 };
+
+        if resume_offset > file_size {
+            let _ = encrypted_stream.finish();
+            encrypted_stream.close();
+
+            return Err(ConnectErrors::FailedToGetTransferRequestResponse { error: "Invalid resume offset".to_string() });
+        }
 
         let mut buffer = [0; 1024];
 
-        NearbyServer::update_progress(&progress_delegate, SendProgressState::Transferring { progress: 0.0 });
+        NearbyServer::update_progress(&progress_delegate, SendProgressState::Transferring { progress: fraction(resume_offset, file_size) });
+
+        let mut all_written: u64 = resume_offset;
+        let mut file_start: u64 = 0;
+        let mut content_hasher = ContentHasher::new();
+        let mut cancelled_by_receiver = false;
+        let mut content_writer = ContentWriter::new(&mut encrypted_stream);
+        let mut read_error = None;
+
+        'files: for source_file in &files {
+            let file_end = file_start + source_file.manifest_entry.file_size;
+
+            let mut file = match File::open(&source_file.path) {
+                Ok(file) => file,
+                Err(error) => {
+                    read_error = Some(format!("Failed to open {}: {}", source_file.manifest_entry.file_name, error));
+                    break;
+                }
+            };
 
-        let mut all_written: usize = 0;
+            // Already received by the receiver during a previous attempt, so it is only hashed
+            let skipped = resume_offset.saturating_sub(file_start).min(source_file.manifest_entry.file_size);
 
-        while let Ok(read_size) = file.read(&mut buffer) {
-            if read_size == 0 {
+            if skipped > 0 && content_hasher.update_from_reader(&mut file, skipped).is_err() {
+                read_error = Some(format!("{} is shorter than announced", source_file.manifest_entry.file_name));
                 break;
             }
 
-            let written_bytes = encrypted_stream.write(&buffer[..read_size])
-                .expect("Failed to write file buffer");
+            file_start = file_end;
+
+            if file_end <= resume_offset {
+                continue;
+            }
+
+            // Never more than announced, as the content of a file that grew would be read as the start of the next one
+            let mut remaining_content = (&mut file).take(source_file.manifest_entry.file_size - skipped);
+
+            loop {
+                let read_size = match remaining_content.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(read_size) => read_size,
+                    Err(error) => {
+                        read_error = Some(format!("Failed to read {}: {}", source_file.manifest_entry.file_name, error));
+                        break 'files;
+                    }
+                };
+
+                if active_send.cancelled.load(Ordering::Relaxed) {
+                    let _ = content_writer.cancel();
+                    break 'files;
+                }
+
+                match content_writer.write_all(&buffer[..read_size]) {
+                    Ok(ContentWrite::Written) => {},
+                    Ok(ContentWrite::CancelledByReceiver) => {
+                        cancelled_by_receiver = true;
+                        break 'files;
+                    },
+                    Err(_) => break 'files
+                }
 
-            if written_bytes <= 0 {
+                content_hasher.update(&buffer[..read_size]);
+                all_written += read_size as u64;
+
+                NearbyServer::update_progress(&progress_delegate, SendProgressState::Transferring { progress: fraction(all_written, file_size) });
+            }
+
+            if remaining_content.limit() > 0 {
+                read_error = Some(format!("{} is shorter than announced", source_file.manifest_entry.file_name));
                 break;
             }
+        }
 
-            all_written += written_bytes;
+        // The receiver discards what it got so far, instead of waiting for content that never comes
+        if let Some(error) = read_error {
+            let _ = content_writer.cancel();
+            let _ = NearbyServer::abort_transfer(&mut encrypted_stream, false, &progress_delegate);
 
-            NearbyServer::update_progress(&progress_delegate, SendProgressState::Transferring { progress: (all_written as f64 / file_size as f64) });
+            return Err(ConnectErrors::FailedToReadFile { error });
         }
 
-        if (all_written as f64) < (file_size as f64) {
-            NearbyServer::update_progress(&progress_delegate, SendProgressState::Cancelled);
+        if all_written < file_size || !NearbyServer::finish_content(&mut content_writer, &mut cancelled_by_receiver) {
+            return NearbyServer::abort_transfer(&mut encrypted_stream, cancelled_by_receiver, &progress_delegate);
+        }
+
+        return NearbyServer::finish_transfer(&mut encrypted_stream, &content_hasher, &progress_delegate);
+    }
+
+    /// Waits until the receiver has the complete content. Returns false if the transfer did not complete.
+    fn finish_content(content_writer: &mut ContentWriter<Box<dyn EncryptedReadWrite>>, cancelled_by_receiver: &mut bool) -> bool {
+        return match content_writer.finish() {
+            Ok(ContentWrite::Written) => true,
+            Ok(ContentWrite::CancelledByReceiver) => {
+                *cancelled_by_receiver = true;
+                false
+            },
+            Err(_) => false
+        };
+    }
+
+    fn abort_transfer(encrypted_stream: &mut Box<dyn EncryptedReadWrite>, cancelled_by_receiver: bool, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let _ = encrypted_stream.finish();
+
+        if cancelled_by_receiver {
+            NearbyServer::update_progress(progress_delegate, SendProgressState::CancelledByReceiver);
         } else {
-            NearbyServer::update_progress(&progress_delegate, SendProgressState::Finished);
+            NearbyServer::update_progress(progress_delegate, SendProgressState::Cancelled);
         }
 
         return Ok(());
     }
 
+    /// Sends the hash of the content and waits until the receiver confirms it.
+    /// Reports `Finished` only once the receiver has verified the content.
+    fn finish_transfer(encrypted_stream: &mut Box<dyn EncryptedReadWrite>, content_hasher: &ContentHasher, progress_delegate: &Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        let mut proto_stream = Stream::new(&mut *encrypted_stream);
+        let _ = proto_stream.send(&content_hasher.trailer());
+
+        let ack = proto_stream.recv::<TransferAck>();
+        let _ = encrypted_stream.finish();
+
+        match ack {
+            Ok(ack) if ack.verified => {},
+            Ok(_) => {
+                NearbyServer::update_progress(progress_delegate, SendProgressState::Cancelled);
+                return Err(ConnectErrors::IntegrityCheckFailed);
+            },
+            Err(error) => {
+                NearbyServer::update_progress(progress_delegate, SendProgressState::Cancelled);
+                return Err(ConnectErrors::FailedToGetTransferAck { error: error.to_string() });
+            }
+        }
+
+        NearbyServer::update_progress(progress_delegate, SendProgressState::Finished);
+
+        return Ok(());
+    }
+
     pub fn handle_incoming_connection(&self, native_stream_handle: Box<dyn NativeStreamDelegate>) {
         let delegate = self.variables.blocking_read().nearby_connection_delegate.clone();
 
@@ -361,42 +1043,100 @@ impl NearbyServer {
             return;
         };
 
-        let file_storage = self.variables.blocking_read().file_storage.clone();
-
-        thread::spawn(move || {
-            let mut encrypted_stream = match initiate_receiver_communication(native_stream_handle) {
-                Ok(request) => request,
-                Err(error) => {
-                    println!("Encryption error {:}", error);
-                    return;
-                }
-            };
-
-            let mut prost_stream = Stream::new(&mut encrypted_stream);
-            let transfer_request = match prost_stream.recv::<TransferRequest>() {
-                Ok(message) => message,
-                Err(error) => {
-                    println!("Error {:}", error);
-                    return;
-                }
-            };
+        let handler = IncomingConnectionHandler {
+            delegate,
+            file_storage: self.variables.blocking_read().file_storage.clone(),
+            identity: self.variables.blocking_read().identity.clone(),
+            trust_store: self.variables.blocking_read().trust_store.clone(),
+            pending_requests: self.variables.blocking_read().pending_requests.clone(),
+            request_timeout: self.variables.blocking_read().request_timeout.clone(),
+            max_transfer_size: self.variables.blocking_read().max_transfer_size.clone(),
+            auto_accept_policy: self.variables.blocking_read().auto_accept_policy.clone(),
+            device_registry: self.variables.blocking_read().device_registry.clone(),
+            active_transfers: self.variables.blocking_read().active_transfers.clone()
+        };
 
-            let connection_request = ConnectionRequest::new(
-                transfer_request,
-                Box::new(encrypted_stream),
-                file_storage.clone()
-            );
+        let native_stream: Arc<dyn NativeStreamDelegate> = Arc::from(native_stream_handle);
 
-            delegate.lock().expect("Failed to lock delegate").received_connection_request(Arc::new(connection_request));
+        thread::spawn(move || {
+            handler.handle(SharedNativeStream(native_stream.clone()), Box::new(SharedNativeStream(native_stream)));
         });
     }
 
     pub fn stop(&self) {
         self.variables.blocking_write().advertise = false;
         self.variables.blocking_write().tcp_server = None;
+        self.variables.blocking_write().mdns_advertiser = None;
+        self.variables.blocking_write().udp_announcer = None;
 
         if let Some(ble_advertisement_implementation) = &self.variables.blocking_read().ble_server_implementation {
             ble_advertisement_implementation.stop_server();
         }
     }
 }
+
+/// A running send, registered by `register_send`.
+#[derive(Clone)]
+struct ActiveSend {
+    id: String,
+    cancelled: Arc<AtomicBool>,
+    /// Closes the connection until the receiver accepted the request
+    connection: Arc<std::sync::Mutex<Option<Box<dyn Close + Send>>>>
+}
+
+impl ActiveSend {
+    /// A send, that was cancelled while connecting, closes its connection right away.
+    fn set_connection(&self, connection: Box<dyn Close + Send>) {
+        *self.connection.lock().expect("Failed to lock connection") = Some(connection);
+
+        if self.cancelled.load(Ordering::Relaxed) {
+            self.close_connection();
+        }
+    }
+
+    fn close_connection(&self) {
+        if let Some(connection) = self.connection.lock().expect("Failed to lock connection").take() {
+            connection.close();
+        }
+    }
+}
+
+/// A local file and how it is announced to the receiver.
+struct SourceFile {
+    path: PathBuf,
+    manifest_entry: FileManifestEntry
+}
+
+/// Collects all files and directories below `directory`, with paths relative to the transferred directory.
+/// Symbolic links are skipped, so a link can neither leave the directory nor create a cycle.
+fn collect_directory(directory: &Path, relative_path: &str, files: &mut Vec<SourceFile>, directories: &mut Vec<String>) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<Result<Vec<_>, io::Error>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let Some(name) = convert_os_str(&entry.file_name()) else {
+            continue;
+        };
+
+        let entry_relative_path = format!("{}/{}", relative_path, name);
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            directories.push(entry_relative_path.clone());
+            collect_directory(&entry.path(), &entry_relative_path, files, directories)?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+
+            files.push(SourceFile {
+                path: entry.path(),
+                manifest_entry: FileManifestEntry {
+                    file_name: entry_relative_path,
+                    file_size: metadata.len(),
+                    modified: resume::modification_time(&metadata)
+                }
+            });
+        }
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,63 @@
+use protocol::communication::{Capability, MessageHeader};
+use protocol::communication::message_header::MessageTypes;
+use crate::errors::IncomingErrors;
+
+/// Version of the protocol spoken by this build.
+/// Has to be increased whenever the messages after the handshake change in an incompatible way.
+///
+/// 1. Initial version
+/// 2. File transfers end with a `TransferTrailer`, which the receiver answers with a `TransferAck`
+/// 3. The content of a transfer is sent as `TransferData` messages, so the sender can cancel it
+/// 4. The receiver answers the content with `TransferControl` messages, so the receiver can cancel it as well
+pub const PROTOCOL_VERSION: i32 = 4;
+
+/// Oldest protocol version this build is still able to talk to.
+/// Older versions can not cancel transfers from the receiver side and do not check the integrity of the content.
+pub const MIN_SUPPORTED_PROTOCOL_VERSION: i32 = 4;
+
+/// Optional features this build supports.
+pub const SUPPORTED_CAPABILITIES: [Capability; 4] = [Capability::MultiFile, Capability::Directories, Capability::Resumption, Capability::Clipboard];
+
+/// What both sides agreed on during the header exchange.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NegotiatedProtocol {
+    pub protocol_version: i32,
+    pub capabilities: Vec<Capability>
+}
+
+impl NegotiatedProtocol {
+    pub fn supports(&self, capability: Capability) -> bool {
+        return self.capabilities.contains(&capability);
+    }
+}
+
+pub fn local_message_header(message_type: MessageTypes) -> MessageHeader {
+    return MessageHeader {
+        protocol_version: PROTOCOL_VERSION,
+        r#type: message_type as i32,
+        capabilities: SUPPORTED_CAPABILITIES.iter().map(|capability| *capability as i32).collect(),
+        min_protocol_version: MIN_SUPPORTED_PROTOCOL_VERSION
+    };
+}
+
+/// Picks the highest version both sides speak, and the capabilities both sides support.
+/// Fails with `InvalidVersion` if one side is too old for the other one.
+pub fn negotiate_protocol(local_header: &MessageHeader, remote_header: &MessageHeader) -> Result<NegotiatedProtocol, IncomingErrors> {
+    if remote_header.protocol_version <= 0 {
+        return Err(IncomingErrors::MissingProtocolVersion);
+    }
+
+    if remote_header.protocol_version < local_header.min_protocol_version
+        || local_header.protocol_version < remote_header.min_protocol_version {
+        return Err(IncomingErrors::InvalidVersion);
+    }
+
+    let capabilities = local_header.capabilities()
+        .filter(|capability| *capability != Capability::UnknownCapability && remote_header.capabilities.contains(&(*capability as i32)))
+        .collect();
+
+    return Ok(NegotiatedProtocol {
+        protocol_version: local_header.protocol_version.min(remote_header.protocol_version),
+        capabilities
+    });
+}
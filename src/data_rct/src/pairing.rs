@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use protocol::discovery::Device;
+use protocol::pairing::{TrustedDevice, TrustedDeviceList};
+use protocol::prost::Message;
+
+const TRUST_STORE_FILE_NAME: &str = "trusted_devices";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrustState {
+    /// The user paired this device and it presented the paired identity key.
+    Paired,
+    /// The device has not been paired (yet).
+    Unknown,
+    /// The device presented a different identity key than the one recorded for its id.
+    KeyChanged
+}
+
+/// Record of known peers, keyed by device id. Only paired devices are persisted.
+///
+/// Every device is recorded the first time it connects (trust on first use), which pins its identity key until the process ends.
+/// A later connection with the same device id but another key is reported as `TrustState::KeyChanged`.
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    devices: HashMap<String, TrustedDevice>
+}
+
+impl TrustStore {
+    /// Store that is never written to disk.
+    pub fn in_memory() -> Self {
+        return Self {
+            path: None,
+            devices: HashMap::new()
+        };
+    }
+
+    pub fn load(config_storage: &str) -> io::Result<Self> {
+        let path = Path::new(config_storage).join(TRUST_STORE_FILE_NAME);
+        let mut devices = HashMap::new();
+
+        if path.exists() {
+            let trusted_devices = match TrustedDeviceList::decode(fs::read(&path)?.as_slice()) {
+                Ok(trusted_devices) => trusted_devices,
+                Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error))
+            };
+
+            // Older versions stored unpaired devices as well
+            for device in trusted_devices.devices.into_iter().filter(|device| device.paired) {
+                devices.insert(device.id.clone(), device);
+            }
+        }
+
+        return Ok(Self {
+            path: Some(path),
+            devices
+        });
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Any host can connect, so recording unpaired devices would let the file grow without limit
+        let trusted_devices = TrustedDeviceList {
+            devices: self.devices.values().filter(|device| device.paired).cloned().collect()
+        };
+
+        return fs::write(path, trusted_devices.encode_to_vec());
+    }
+
+    pub fn get_trust_state(&self, device_id: &str, identity_public_key: &[u8]) -> TrustState {
+        let Some(trusted_device) = self.devices.get(device_id) else {
+            return TrustState::Unknown;
+        };
+
+        if trusted_device.identity_public_key != identity_public_key {
+            return TrustState::KeyChanged;
+        }
+
+        if trusted_device.paired {
+            return TrustState::Paired;
+        }
+
+        return TrustState::Unknown;
+    }
+
+    /// Returns the trust state of the device and records it in memory, if it has never been seen before.
+    pub fn check_device(&mut self, device: &Device, identity_public_key: &[u8]) -> TrustState {
+        if !self.devices.contains_key(&device.id) {
+            self.devices.insert(device.id.clone(), TrustedDevice {
+                id: device.id.clone(),
+                name: device.name.clone(),
+                identity_public_key: identity_public_key.to_vec(),
+                first_seen: current_timestamp(),
+                paired: false
+            });
+        }
+
+        return self.get_trust_state(&device.id, identity_public_key);
+    }
+
+    /// Pairs the device with the given identity key. This replaces a previously recorded key.
+    pub fn pair(&mut self, device: &Device, identity_public_key: &[u8]) -> io::Result<()> {
+        let first_seen = match self.devices.get(&device.id) {
+            Some(trusted_device) if trusted_device.identity_public_key == identity_public_key => trusted_device.first_seen,
+            _ => current_timestamp()
+        };
+
+        self.devices.insert(device.id.clone(), TrustedDevice {
+            id: device.id.clone(),
+            name: device.name.clone(),
+            identity_public_key: identity_public_key.to_vec(),
+            first_seen,
+            paired: true
+        });
+
+        return self.save();
+    }
+
+    pub fn forget(&mut self, device_id: &str) -> io::Result<()> {
+        if self.devices.remove(device_id).is_some_and(|device| device.paired) {
+            return self.save();
+        }
+
+        return Ok(());
+    }
+
+    /// Paired devices only.
+    pub fn get_devices(&self) -> Vec<TrustedDevice> {
+        let mut devices: Vec<TrustedDevice> = self.devices.values().filter(|device| device.paired).cloned().collect();
+        devices.sort_by_key(|device| device.first_seen);
+
+        return devices;
+    }
+}
+
+fn current_timestamp() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+}
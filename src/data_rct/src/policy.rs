@@ -0,0 +1,159 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use protocol::communication::transfer_request::Intent;
+use crate::connection_request::ConnectionRequest;
+use crate::nearby::ConnectionIntentType;
+use crate::pairing::TrustState;
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// What happens with an incoming request, without asking the user.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolicyDecision {
+    Accept,
+    Decline,
+    /// The request is passed to `NearbyConnectionDelegate` as usual.
+    Ask
+}
+
+/// Daily time span in minutes after midnight, e.g. 22:00 to 07:00 is `start_minute` 1320 and `end_minute` 420.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeWindow {
+    pub start_minute: u32,
+    /// Exclusive. Smaller than `start_minute` for windows that span midnight.
+    pub end_minute: u32,
+    /// Offset of the local time zone to UTC, so the window follows the clock of the user.
+    pub utc_offset_minutes: i32
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let minutes_since_epoch = time.duration_since(UNIX_EPOCH)
+            .map(|duration| (duration.as_secs() / 60) as i64)
+            .unwrap_or(0);
+
+        let minute = (minutes_since_epoch + self.utc_offset_minutes as i64).rem_euclid(MINUTES_PER_DAY) as u32;
+
+        if self.start_minute <= self.end_minute {
+            return self.start_minute <= minute && minute < self.end_minute;
+        }
+
+        return minute >= self.start_minute || minute < self.end_minute;
+    }
+}
+
+/// A rule matches a request if all of its conditions match. Conditions that are not set match every request.
+/// `Accept` rules only ever match paired senders.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyRule {
+    /// Only matches paired senders, as the device id alone can be claimed by any device.
+    pub sender_id: Option<String>,
+    /// Only matches senders, that are paired and presented the paired identity key.
+    pub trusted_only: bool,
+    pub intent_type: Option<ConnectionIntentType>,
+    /// Largest transfer in bytes, that matches.
+    pub max_size: Option<u64>,
+    /// Every file of the transfer has to have one of these extensions, compared case-insensitively and without the dot.
+    /// Clipboard transfers have no files and never match a rule with extensions.
+    pub file_extensions: Vec<String>,
+    pub time_window: Option<TimeWindow>,
+    pub decision: PolicyDecision
+}
+
+/// What the policy knows about an incoming request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestDetails {
+    pub sender_id: String,
+    pub trust_state: TrustState,
+    pub intent_type: ConnectionIntentType,
+    pub size: u64,
+    pub file_names: Vec<String>
+}
+
+impl RequestDetails {
+    pub fn from_request(connection_request: &ConnectionRequest) -> Self {
+        let file_names = match connection_request.get_intent() {
+            Intent::FileTransfer(_) => connection_request.get_file_manifest().into_iter().map(|file| file.file_name).collect(),
+            Intent::Clipboard(_) => vec![]
+        };
+
+        return Self {
+            sender_id: connection_request.get_sender().id,
+            trust_state: connection_request.get_trust_state(),
+            intent_type: connection_request.get_intent_type(),
+            size: connection_request.get_transfer_size(),
+            file_names
+        };
+    }
+}
+
+impl PolicyRule {
+    pub fn matches(&self, request_details: &RequestDetails, time: SystemTime) -> bool {
+        // Accepting skips the verification code, so only paired senders are accepted without asking the user
+        if self.decision == PolicyDecision::Accept && request_details.trust_state != TrustState::Paired {
+            return false;
+        }
+
+        if let Some(sender_id) = &self.sender_id {
+            if *sender_id != request_details.sender_id || request_details.trust_state != TrustState::Paired {
+                return false;
+            }
+        }
+
+        if self.trusted_only && request_details.trust_state != TrustState::Paired {
+            return false;
+        }
+
+        if self.intent_type.as_ref().is_some_and(|intent_type| *intent_type != request_details.intent_type) {
+            return false;
+        }
+
+        if self.max_size.is_some_and(|max_size| request_details.size > max_size) {
+            return false;
+        }
+
+        if !self.file_extensions.is_empty() && !self.matches_file_extensions(&request_details.file_names) {
+            return false;
+        }
+
+        let Some(time_window) = &self.time_window else {
+            return true;
+        };
+
+        return time_window.contains(time);
+    }
+
+    fn matches_file_extensions(&self, file_names: &[String]) -> bool {
+        if file_names.is_empty() {
+            return false;
+        }
+
+        return file_names.iter().all(|file_name| {
+            let Some(extension) = Path::new(file_name).extension().and_then(|extension| extension.to_str()) else {
+                return false;
+            };
+
+            return self.file_extensions.iter().any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(extension));
+        });
+    }
+}
+
+/// Ordered list of rules. The first matching rule decides, requests without a matching rule are passed to the user.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AutoAcceptPolicy {
+    pub rules: Vec<PolicyRule>
+}
+
+impl AutoAcceptPolicy {
+    pub fn new(rules: Vec<PolicyRule>) -> Self {
+        return Self {
+            rules
+        };
+    }
+
+    pub fn decide(&self, request_details: &RequestDetails, time: SystemTime) -> PolicyDecision {
+        return self.rules.iter()
+            .find(|rule| rule.matches(request_details, time))
+            .map_or(PolicyDecision::Ask, |rule| rule.decision);
+    }
+}
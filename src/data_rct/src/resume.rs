@@ -0,0 +1,232 @@
+use std::fs::{self, DirEntry};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use protocol::communication::FileManifestEntry;
+use protocol::prost::Message;
+use protocol::resume::ResumeState;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use crate::file_names::{sanitize_path, LocalPaths};
+
+const PARTIAL_FILE_EXTENSION: &str = ".part";
+const RESUME_STATE_EXTENSION: &str = ".resume";
+
+/// Partial files, that no resume state refers to, are only removed once they were not written to for this long,
+/// so a running transfer, that can not be resumed, keeps its files.
+pub const STALE_PARTIAL_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Transfers, that were not resumed for this long, are given up.
+pub const RESUME_STATE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Derives the id of a transfer from the receiver and the source files.
+/// Sending the same files to the same device again results in the same id, which allows resuming.
+pub fn transfer_id(receiver_id: &str, source_paths: &[PathBuf]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(receiver_id.as_bytes());
+
+    for source_path in source_paths {
+        let source_path = fs::canonicalize(source_path).unwrap_or_else(|_| source_path.clone());
+        let source_path = source_path.to_string_lossy();
+
+        hasher.update((source_path.len() as u64).to_be_bytes());
+        hasher.update(source_path.as_bytes());
+    }
+
+    return to_hex(&hasher.finalize());
+}
+
+/// Last modification of the file in unix milliseconds, or 0 if the platform does not provide it.
+pub fn modification_time(metadata: &fs::Metadata) -> u64 {
+    return metadata.modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0);
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+}
+
+/// The transfer id is used as file name, so only ids created by `transfer_id` are accepted.
+fn is_valid_transfer_id(transfer_id: &str) -> bool {
+    return transfer_id.len() == 64 && transfer_id.chars().all(|character| character.is_ascii_hexdigit());
+}
+
+/// Names the resume state and partial files of a transfer. The sender is part of the name,
+/// so a sender can not pick the transfer id of another sender and write to its files.
+pub fn storage_id(transfer_id: &str, sender_identity_public_key: &[u8]) -> Option<String> {
+    if !is_valid_transfer_id(transfer_id) {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(transfer_id.as_bytes());
+    hasher.update(sender_identity_public_key);
+
+    return Some(to_hex(&hasher.finalize()));
+}
+
+/// `storage_id` has to be created by `storage_id`.
+fn state_path(file_storage: &str, storage_id: &str) -> Option<PathBuf> {
+    if !is_valid_transfer_id(storage_id) {
+        return None;
+    }
+
+    return Some(Path::new(file_storage).join(format!(".{}{}", storage_id, RESUME_STATE_EXTENSION)));
+}
+
+/// Names the partial files of a transfer. Requests of older senders have no transfer id, so a random one is used.
+pub fn partial_files_id(transfer_id: &str, sender_identity_public_key: &[u8]) -> String {
+    if let Some(storage_id) = storage_id(transfer_id, sender_identity_public_key) {
+        return storage_id;
+    }
+
+    return to_hex(&Sha256::digest(Uuid::new_v4().as_bytes()));
+}
+
+/// Hidden file in the file storage, that holds the received data of a file until the transfer is complete.
+/// `partial_files_id` has to be created by `partial_files_id` or `storage_id`.
+pub fn partial_file_path(file_storage: &str, partial_files_id: &str, file_index: usize) -> PathBuf {
+    return Path::new(file_storage).join(format!(".{}.{}{}", partial_files_id, file_index, PARTIAL_FILE_EXTENSION));
+}
+
+pub fn remove_partial_files(file_storage: &str, partial_files_id: &str, file_count: usize) {
+    for file_index in 0..file_count {
+        let _ = fs::remove_file(partial_file_path(file_storage, partial_files_id, file_index));
+    }
+}
+
+/// Removes partial files, that can not be resumed anymore, e.g. because the app was terminated during a transfer.
+/// Resume states expire after `RESUME_STATE_MAX_AGE`, and partial files without a resume state after `STALE_PARTIAL_FILE_AGE`.
+pub fn remove_stale_partial_files(file_storage: &str) {
+    let Ok(entries) = fs::read_dir(file_storage) else {
+        return;
+    };
+
+    let entries: Vec<DirEntry> = entries.flatten().collect();
+
+    // States first, so the partial files of an expired state are removed in the same pass
+    for entry in &entries {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        if file_name.starts_with('.') && file_name.ends_with(RESUME_STATE_EXTENSION) && is_older_than(entry, RESUME_STATE_MAX_AGE) {
+            println!("Removing expired resume state {}", file_name);
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+
+    for entry in &entries {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+
+        let Some(partial_files_id) = file_name.strip_suffix(PARTIAL_FILE_EXTENSION)
+            .and_then(|name| name.strip_prefix('.'))
+            .and_then(|name| name.split('.').next()) else {
+            continue;
+        };
+
+        if load_state(file_storage, partial_files_id).is_none() && is_older_than(entry, STALE_PARTIAL_FILE_AGE) {
+            println!("Removing stale partial file {}", file_name);
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Whether the file was last modified longer than `age` ago. Files with an unknown age are kept.
+fn is_older_than(entry: &DirEntry, age: Duration) -> bool {
+    return entry.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|elapsed| elapsed > age);
+}
+
+pub fn load_state(file_storage: &str, storage_id: &str) -> Option<ResumeState> {
+    let path = state_path(file_storage, storage_id)?;
+    let data = fs::read(path).ok()?;
+
+    return ResumeState::decode(data.as_slice()).ok();
+}
+
+pub fn save_state(file_storage: &str, state: &ResumeState) -> io::Result<()> {
+    let path = storage_id(&state.transfer_id, &state.sender_identity_public_key)
+        .and_then(|storage_id| state_path(file_storage, &storage_id));
+
+    let Some(path) = path else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid transfer id"));
+    };
+
+    return fs::write(path, state.encode_to_vec());
+}
+
+/// Where the files of the stored transfer are located. States of older versions used the file names of the manifest.
+pub fn local_paths(state: &ResumeState) -> LocalPaths {
+    if state.local_file_names.len() != state.files.len() {
+        return LocalPaths {
+            files: state.files.iter().map(|file| sanitize_path(&file.file_name)).collect(),
+            directories: state.local_directories.clone()
+        };
+    }
+
+    return LocalPaths {
+        files: state.local_file_names.clone(),
+        directories: state.local_directories.clone()
+    };
+}
+
+pub fn remove_state(file_storage: &str, storage_id: &str) {
+    if let Some(path) = state_path(file_storage, storage_id) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Number of bytes of the stored state, that are actually present on disk.
+/// Completed files have to be complete, and the file that was interrupted has to be at least as long as recorded.
+pub fn verified_offset(file_storage: &str, state: &ResumeState) -> u64 {
+    let Some(storage_id) = storage_id(&state.transfer_id, &state.sender_identity_public_key) else {
+        return 0;
+    };
+
+    let mut position: u64 = 0;
+
+    for (file_index, file) in state.files.iter().enumerate() {
+        let length = fs::metadata(partial_file_path(file_storage, &storage_id, file_index))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        if position + file.file_size <= state.offset {
+            if length != file.file_size {
+                return position;
+            }
+
+            position += file.file_size;
+            continue;
+        }
+
+        return position + length.min(state.offset - position);
+    }
+
+    return position;
+}
+
+/// Offset to resume the transfer from, or 0 if there is nothing to resume.
+/// A transfer is only resumed for the same sender, and if none of the source files changed.
+pub fn resume_offset(file_storage: &str, transfer_id: &str, sender_identity_public_key: &[u8], files: &[FileManifestEntry]) -> u64 {
+    let Some(storage_id) = storage_id(transfer_id, sender_identity_public_key) else {
+        return 0;
+    };
+
+    let Some(state) = load_state(file_storage, &storage_id) else {
+        return 0;
+    };
+
+    if state.sender_identity_public_key != sender_identity_public_key || state.files != files {
+        remove_state(file_storage, &storage_id);
+        return 0;
+    }
+
+    return verified_offset(file_storage, &state);
+}
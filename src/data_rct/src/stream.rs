@@ -1,6 +1,7 @@
 use std::fmt::Debug;
 use std::io;
 use std::io::{Read, Write};
+use std::sync::Arc;
 
 pub trait Close {
     fn close(&self);
@@ -41,3 +42,35 @@ impl Close for Box<dyn NativeStreamDelegate> {
         self.disconnect();
     }
 }
+
+/// A native stream, that can be disconnected by another thread while it is read from.
+pub struct SharedNativeStream(pub Arc<dyn NativeStreamDelegate>);
+
+impl Read for SharedNativeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = NativeStreamDelegate::read(&*self.0, buf.len() as u64);
+
+        let len = std::cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+
+        Ok(len)
+    }
+}
+
+impl Write for SharedNativeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return Ok(NativeStreamDelegate::write(&*self.0, buf.to_vec()) as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        NativeStreamDelegate::flush(&*self.0);
+
+        return Ok(());
+    }
+}
+
+impl Close for SharedNativeStream {
+    fn close(&self) {
+        self.0.disconnect();
+    }
+}
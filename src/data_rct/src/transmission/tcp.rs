@@ -1,25 +1,19 @@
 use std::{io, thread};
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use prost_stream::Stream;
-use protocol::communication::TransferRequest;
 
-use crate::communication::initiate_receiver_communication;
-use crate::connection_request::ConnectionRequest;
-use crate::nearby::NearbyConnectionDelegate;
+use crate::connection_request::IncomingConnectionHandler;
 use crate::stream::Close;
 
 pub struct TcpServer {
     pub port: u16,
     listener: TcpListener,
-    delegate: Arc<Mutex<Box<dyn NearbyConnectionDelegate>>>,
-    file_storage: String
+    handler: IncomingConnectionHandler
 }
 
 impl TcpServer {
-    pub(crate) async fn new(delegate: Arc<Mutex<Box<dyn NearbyConnectionDelegate>>>, file_storage: String) -> Result<TcpServer, io::Error> {
+    pub(crate) async fn new(handler: IncomingConnectionHandler) -> Result<TcpServer, io::Error> {
         let addresses = [
             SocketAddr::from(([0, 0, 0, 0], 80)),
             SocketAddr::from(([0, 0, 0, 0], 8080)),
@@ -33,15 +27,13 @@ impl TcpServer {
         return Ok(Self {
             port,
             listener,
-            delegate,
-            file_storage
+            handler
         });
     }
 
     pub fn start_loop(&self) {
         let listener = self.listener.try_clone().expect("Failed to clone listener");
-        let delegate = self.delegate.clone();
-        let file_storage = self.file_storage.clone();
+        let handler = self.handler.clone();
 
         thread::spawn(move || {
             loop {
@@ -49,30 +41,16 @@ impl TcpServer {
                     continue
                 };
 
-                let mut encrypted_stream = match initiate_receiver_communication(tcp_stream) {
-                    Ok(request) => request,
-                    Err(error) => {
-                        println!("Encryption error {:}", error);
-                        continue;
-                    }
-                };
-
-                let mut prost_stream = Stream::new(&mut encrypted_stream);
-                let transfer_request = match prost_stream.recv::<TransferRequest>() {
-                    Ok(message) => message,
-                    Err(error) => {
-                        println!("Error {:}", error);
-                        continue;
-                    }
+                let Ok(shutdown_stream) = tcp_stream.try_clone() else {
+                    continue
                 };
 
-                let connection_request = ConnectionRequest::new(
-                    transfer_request,
-                    Box::new(encrypted_stream),
-                    file_storage.clone()
-                );
+                // A connection waits for the user of the sender, so it must not block the next one
+                let handler = handler.clone();
 
-                delegate.lock().expect("Failed to lock").received_connection_request(Arc::new(connection_request));
+                thread::spawn(move || {
+                    handler.handle(tcp_stream, Box::new(TcpShutdown(shutdown_stream)));
+                });
             }
         });
     }
@@ -95,3 +73,12 @@ impl Close for TcpStream {
         // Do nothing. TCPStream closes automatically.
     }
 }
+
+/// Shuts down a connection, that is used by another thread, so its blocking reads return.
+pub struct TcpShutdown(pub TcpStream);
+
+impl Close for TcpShutdown {
+    fn close(&self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
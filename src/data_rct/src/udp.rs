@@ -0,0 +1,158 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use protocol::discovery::{DeviceConnectionInfo, DeviceDiscoveryMessage, DiscoveryQuery};
+use protocol::discovery::device_discovery_message::Content;
+use protocol::prost::Message;
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::errors::DiscoverySetupError;
+
+/// Administratively scoped multicast group, so announcements stay on the local network.
+pub const UDP_MULTICAST_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+pub const UDP_DISCOVERY_PORT: u16 = 42499;
+
+/// How often an `UdpAnnouncer` announces itself without being asked.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the threads wait for a datagram, before checking whether they were stopped.
+const RECEIVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+const MAX_DATAGRAM_SIZE: usize = 2048;
+
+/// Joins the multicast group. The port is shared, so several processes on one host can announce and browse at the same time.
+fn multicast_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, UDP_DISCOVERY_PORT)).into())?;
+    socket.join_multicast_v4(&UDP_MULTICAST_ADDRESS, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+
+    return Ok(socket.into());
+}
+
+fn open_socket() -> Result<UdpSocket, DiscoverySetupError> {
+    return multicast_socket().map_err(|error| {
+        println!("Unable to open UDP discovery socket: {:?}", error);
+        DiscoverySetupError::UnableToSetupUdp
+    });
+}
+
+/// Messages are length delimited, like the BLE advertisement, so both are parsed the same way.
+fn send(socket: &UdpSocket, content: Content) {
+    let message = DeviceDiscoveryMessage {
+        content: Some(content)
+    }.encode_length_delimited_to_vec();
+
+    if let Err(error) = socket.send_to(&message, (UDP_MULTICAST_ADDRESS, UDP_DISCOVERY_PORT)) {
+        println!("Unable to send UDP discovery message: {:?}", error);
+    }
+}
+
+fn is_query(data: &[u8]) -> bool {
+    return matches!(
+        DeviceDiscoveryMessage::decode_length_delimited(data),
+        Ok(DeviceDiscoveryMessage { content: Some(Content::Query(_)) })
+    );
+}
+
+/// Announces a `NearbyServer` to the multicast group when it starts, every `ANNOUNCE_INTERVAL`
+/// and whenever a `UdpBrowser` asks. Dropping it sends a goodbye with the device id.
+pub struct UdpAnnouncer {
+    socket: UdpSocket,
+    device_id: String,
+    running: Arc<AtomicBool>
+}
+
+impl UdpAnnouncer {
+    pub fn start(device_connection_info: &DeviceConnectionInfo) -> Result<Self, DiscoverySetupError> {
+        let Some(device) = &device_connection_info.device else {
+            return Err(DiscoverySetupError::UnableToSetupUdp);
+        };
+
+        let socket = open_socket()?;
+        let thread_socket = socket.try_clone().map_err(|_| DiscoverySetupError::UnableToSetupUdp)?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let device_connection_info = device_connection_info.clone();
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+            let mut next_announcement = Instant::now();
+
+            while thread_running.load(Ordering::Relaxed) {
+                if Instant::now() >= next_announcement {
+                    send(&thread_socket, Content::DeviceConnectionInfo(device_connection_info.clone()));
+                    next_announcement = Instant::now() + ANNOUNCE_INTERVAL;
+                }
+
+                // Timeouts only wake the loop up
+                let Ok(length) = thread_socket.recv(&mut buffer) else {
+                    continue;
+                };
+
+                if is_query(&buffer[..length]) {
+                    next_announcement = Instant::now();
+                }
+            }
+        });
+
+        return Ok(Self {
+            socket,
+            device_id: device.id.clone(),
+            running
+        });
+    }
+}
+
+impl Drop for UdpAnnouncer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        send(&self.socket, Content::OfflineDeviceId(self.device_id.clone()));
+    }
+}
+
+/// Listens to the multicast group until it is dropped, and asks all devices to announce themselves when it starts.
+pub struct UdpBrowser {
+    running: Arc<AtomicBool>
+}
+
+impl UdpBrowser {
+    /// `message_received` is called with every length delimited `DeviceDiscoveryMessage` received, including queries of other browsers.
+    pub fn start<T>(message_received: T) -> Result<Self, DiscoverySetupError> where T: Fn(Vec<u8>) + Send + 'static {
+        let socket = open_socket()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+
+        send(&socket, Content::Query(DiscoveryQuery {}));
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+
+            while thread_running.load(Ordering::Relaxed) {
+                let Ok(length) = socket.recv(&mut buffer) else {
+                    continue;
+                };
+
+                message_received(buffer[..length].to_vec());
+            }
+        });
+
+        return Ok(Self {
+            running
+        });
+    }
+}
+
+impl Drop for UdpBrowser {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
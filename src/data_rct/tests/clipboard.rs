@@ -0,0 +1,32 @@
+use data_rct::clipboard::{is_valid_mime_type, validate_clipboard, ClipboardItem, MAX_CLIPBOARD_SIZE, MIME_TYPE_HTML};
+use data_rct::protocol::communication::{ClipboardRepresentation, ClipboardTransferIntent};
+
+#[test]
+pub fn mime_types_are_validated() {
+    assert!(is_valid_mime_type("text/plain"));
+    assert!(is_valid_mime_type("text/plain; charset=utf-8"));
+    assert!(is_valid_mime_type("application/vnd.oasis.opendocument.text"));
+    assert!(!is_valid_mime_type("text"));
+    assert!(!is_valid_mime_type("text/"));
+    assert!(!is_valid_mime_type("text/pl ain"));
+}
+
+#[test]
+pub fn clipboard_is_validated() {
+    let html = ClipboardItem { mime_type: MIME_TYPE_HTML.to_string(), data: b"<b>Hello</b>".to_vec() };
+    let valid = ClipboardTransferIntent {
+        representations: vec![ClipboardItem::text("Hello").representation(), html.representation()]
+    };
+
+    assert!(validate_clipboard(&valid).is_ok());
+    assert!(validate_clipboard(&ClipboardTransferIntent { representations: vec![] }).is_err());
+
+    let too_large = ClipboardTransferIntent {
+        representations: vec![
+            ClipboardRepresentation { mime_type: "image/png".to_string(), size: MAX_CLIPBOARD_SIZE },
+            ClipboardRepresentation { mime_type: "text/plain".to_string(), size: u64::MAX }
+        ]
+    };
+
+    assert!(validate_clipboard(&too_large).is_err());
+}
@@ -0,0 +1,67 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use data_rct::communication::{initiate_receiver_communication, initiate_sender_communication_with_modes};
+use data_rct::identity::DeviceIdentity;
+use data_rct::negotiation::PROTOCOL_VERSION;
+use data_rct::protocol::communication::HandshakeMode;
+
+#[test]
+pub fn noise_handshake_and_bidirectional_messages() {
+    handshake_and_bidirectional_messages(HandshakeMode::NoiseXx);
+}
+
+#[test]
+pub fn triple_diffie_hellman_handshake_and_bidirectional_messages() {
+    handshake_and_bidirectional_messages(HandshakeMode::DataRct3dh);
+}
+
+fn handshake_and_bidirectional_messages(mode: HandshakeMode) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+    let address = listener.local_addr().expect("Failed to get listener address");
+
+    let sender_identity = DeviceIdentity::generate();
+    let receiver_identity = Arc::new(DeviceIdentity::generate());
+
+    let sender_public_key = sender_identity.public_key();
+    let receiver_public_key = receiver_identity.public_key();
+
+    let receiver = thread::spawn(move || {
+        let (tcp_stream, _) = listener.accept().expect("Failed to accept connection");
+        let mut encrypted_stream = initiate_receiver_communication(tcp_stream, &receiver_identity)
+            .expect("Receiver handshake failed");
+
+        let handshake_details = encrypted_stream.handshake_details.clone().expect("Missing handshake details");
+        assert_eq!(handshake_details.peer_identity_public_key, sender_public_key);
+
+        let mut buffer = [0u8; 4];
+        encrypted_stream.read_exact(&mut buffer).expect("Failed to read ping");
+        assert_eq!(&buffer, b"ping");
+
+        encrypted_stream.write_all(b"pong").expect("Failed to write pong");
+        encrypted_stream.finish().expect("Failed to finish stream");
+
+        return handshake_details;
+    });
+
+    let tcp_stream = TcpStream::connect(address).expect("Failed to connect");
+    let mut encrypted_stream = futures::executor::block_on(initiate_sender_communication_with_modes(tcp_stream, &sender_identity, &[mode]))
+        .expect("Sender handshake failed");
+
+    let handshake_details = encrypted_stream.handshake_details.clone().expect("Missing handshake details");
+    assert_eq!(handshake_details.peer_identity_public_key, receiver_public_key);
+
+    encrypted_stream.write_all(b"ping").expect("Failed to write ping");
+
+    let mut response = Vec::new();
+    encrypted_stream.read_to_end(&mut response).expect("Failed to read pong");
+    assert_eq!(response, b"pong");
+
+    let receiver_handshake_details = receiver.join().expect("Receiver thread panicked");
+    assert_eq!(handshake_details.transcript_hash, receiver_handshake_details.transcript_hash);
+    assert_eq!(handshake_details.verification_code, receiver_handshake_details.verification_code);
+    assert_eq!(handshake_details.verification_code.len(), 6);
+    assert_eq!(handshake_details.negotiated_protocol, receiver_handshake_details.negotiated_protocol);
+    assert_eq!(handshake_details.negotiated_protocol.protocol_version, PROTOCOL_VERSION);
+}
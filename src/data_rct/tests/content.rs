@@ -0,0 +1,109 @@
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use data_rct::content::{ContentRead, ContentReader, ContentWrite, ContentWriter, CONTENT_WINDOW_SIZE};
+use crate::helper::MemoryStream;
+
+mod helper;
+
+fn read_all(stream: &mut MemoryStream) -> (Vec<u8>, bool) {
+    let mut content_reader = ContentReader::new(stream);
+    let mut content = Vec::new();
+    let mut buffer = [0u8; 7];
+
+    loop {
+        match content_reader.read(&mut buffer) {
+            Ok(ContentRead::Data(0)) | Err(_) => return (content, false),
+            Ok(ContentRead::Data(read_size)) => content.extend_from_slice(&buffer[..read_size]),
+            Ok(ContentRead::Cancelled) => return (content, true)
+        }
+    }
+}
+
+#[test]
+pub fn sender_cancels_content() {
+    let mut stream = MemoryStream::new();
+
+    let mut content_writer = ContentWriter::new(&mut stream);
+    content_writer.write_all(b"Hello, ").expect("Failed to write content");
+    content_writer.write_all(b"World!").expect("Failed to write content");
+    content_writer.cancel().expect("Failed to cancel");
+
+    stream.set_position(0);
+
+    let (content, cancelled) = read_all(&mut stream);
+    assert_eq!(content, b"Hello, World!");
+    assert!(cancelled);
+}
+
+fn connected_streams() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+    let sender_stream = TcpStream::connect(listener.local_addr().expect("Failed to get listener address")).expect("Failed to connect");
+    let (receiver_stream, _) = listener.accept().expect("Failed to accept connection");
+
+    return (sender_stream, receiver_stream);
+}
+
+#[test]
+pub fn receiver_cancels_acknowledged_content() {
+    let (mut sender_stream, mut receiver_stream) = connected_streams();
+
+    let receiver = thread::spawn(move || {
+        let mut content_reader = ContentReader::new(&mut receiver_stream);
+        let mut buffer = [0u8; 1024];
+        let mut received: u64 = 0;
+
+        while received < CONTENT_WINDOW_SIZE {
+            match content_reader.read(&mut buffer).expect("Failed to read content") {
+                ContentRead::Data(read_size) => received += read_size as u64,
+                ContentRead::Cancelled => panic!("Sender cancelled")
+            }
+        }
+
+        content_reader.cancel().expect("Failed to cancel");
+    });
+
+    let mut content_writer = ContentWriter::new(&mut sender_stream);
+    let chunk = [7u8; 1024];
+    let mut result = ContentWrite::Written;
+
+    for _ in 0..(CONTENT_WINDOW_SIZE * 16 / 1024) {
+        result = content_writer.write_all(&chunk).expect("Failed to write content");
+
+        if result == ContentWrite::CancelledByReceiver {
+            break;
+        }
+    }
+
+    assert_eq!(result, ContentWrite::CancelledByReceiver);
+    receiver.join().expect("Receiver thread panicked");
+}
+
+#[test]
+pub fn receiver_confirms_acknowledged_content() {
+    let (mut sender_stream, mut receiver_stream) = connected_streams();
+    let content_size = CONTENT_WINDOW_SIZE * 3 + 100;
+
+    let receiver = thread::spawn(move || {
+        let mut content_reader = ContentReader::new(&mut receiver_stream);
+        let mut buffer = [0u8; 1000];
+        let mut received: u64 = 0;
+
+        while received < content_size {
+            if let ContentRead::Data(read_size) = content_reader.read(&mut buffer).expect("Failed to read content") {
+                received += read_size as u64;
+            }
+        }
+
+        content_reader.finish().expect("Failed to finish");
+    });
+
+    let mut content_writer = ContentWriter::new(&mut sender_stream);
+    let content = vec![1u8; content_size as usize];
+
+    for chunk in content.chunks(1024) {
+        assert_eq!(content_writer.write_all(chunk).expect("Failed to write content"), ContentWrite::Written);
+    }
+
+    assert_eq!(content_writer.finish().expect("Failed to finish"), ContentWrite::Written);
+    receiver.join().expect("Receiver thread panicked");
+}
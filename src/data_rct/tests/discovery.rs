@@ -1,62 +1,249 @@
-use std::time::{Duration, Instant};
-use data_rct::discovery::{DeviceInfo, Discovery, DiscoveryMethod};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+use data_rct::connection_request::ConnectionRequest;
+use data_rct::device_registry::DeviceRegistry;
+use data_rct::discovery::{Discovery, DiscoveryMethod};
+use data_rct::clipboard::ClipboardItem;
+use data_rct::{DeclineReason, DiscoveryDelegate};
+use data_rct::errors::ConnectErrors;
+use data_rct::nearby::{NearbyConnectionDelegate, NearbyServer};
+use data_rct::protocol::discovery::{Device, DeviceConnectionInfo, DeviceDiscoveryMessage};
+use data_rct::protocol::discovery::device_discovery_message::Content;
+use data_rct::protocol::prost::Message;
+use crate::helper::wait_for;
+
+mod helper;
 
 const FOREIGN_DEVICE_ID: &str = "39FAC7A0-E581-4676-A9C5-0F6DC667567F";
 
-fn get_my_device() -> DeviceInfo {
-    return DeviceInfo {
-        id: "B53CCB62-7DAB-4403-9FEB-F336834DB41F".to_string(),
-        name: "Rust Device".to_string(),
-        port: 42,
-        device_type: "computer".to_string(),
-        ip_address: "1.2.3.4".to_string()
-    };
+#[derive(Debug)]
+struct DeviceEvents {
+    events: Arc<Mutex<Vec<String>>>
+}
+
+impl DiscoveryDelegate for DeviceEvents {
+    fn device_added(&self, value: Device) {
+        self.events.lock().unwrap().push(format!("added {}", value.id));
+    }
+
+    fn device_removed(&self, device_id: String) {
+        self.events.lock().unwrap().push(format!("removed {}", device_id));
+    }
+}
+
+#[derive(Debug)]
+struct IgnoreRequests;
+
+impl NearbyConnectionDelegate for IgnoreRequests {
+    fn received_verification_code(&self, _sender: Device, _verification_code: String) {}
+    fn verification_aborted(&self, _sender: Device) {}
+    fn received_connection_request(&self, _request: Arc<ConnectionRequest>) {}
+    fn declined_connection_request(&self, _sender: Option<Device>, _reason: DeclineReason) {}
+}
+
+fn discovery_message() -> Vec<u8> {
+    return DeviceDiscoveryMessage {
+        content: Some(Content::DeviceConnectionInfo(DeviceConnectionInfo {
+            device: Some(Device {
+                id: FOREIGN_DEVICE_ID.to_string(),
+                name: "Discovery-Test Advertiser".to_string(),
+                device_type: 0
+            }),
+            tcp: None,
+            ble: None,
+            identity_public_key: vec![]
+        }))
+    }.encode_length_delimited_to_vec();
+}
+
+#[test]
+fn devices_expire() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let mut discovery = Discovery::new(Some(Box::new(DeviceEvents { events: events.clone() }))).unwrap();
+    discovery.set_device_ttl(1);
+    discovery.start(vec![]);
+
+    discovery.parse_discovery_message(discovery_message(), None);
+
+    let discovered_devices = discovery.get_discovered_devices();
+    assert_eq!(discovered_devices.len(), 1);
+    assert_eq!(discovered_devices[0].device.id, FOREIGN_DEVICE_ID);
+    assert_eq!(discovered_devices[0].medium, DiscoveryMethod::BLE);
+    assert!(discovered_devices[0].last_seen > 0);
+
+    // Seen again, so the device is neither added twice nor expired early
+    sleep(Duration::from_millis(700));
+    discovery.parse_discovery_message(discovery_message(), None);
+    assert!(!wait_for(&events, |event| *event == format!("removed {}", FOREIGN_DEVICE_ID), Duration::from_millis(700)));
+
+    assert!(wait_for(&events, |event| *event == format!("removed {}", FOREIGN_DEVICE_ID), Duration::from_secs(5)));
+    assert_eq!(*events.lock().unwrap(), vec![format!("added {}", FOREIGN_DEVICE_ID), format!("removed {}", FOREIGN_DEVICE_ID)]);
+    assert!(discovery.get_devices().is_empty());
+
+    discovery.stop();
 }
 
-fn setup_foreign_discovery(method: DiscoveryMethod) -> Discovery {
-    let discovery = Discovery::new(DeviceInfo {
+#[test]
+fn expiry_stops_with_discovery() {
+    let events = Arc::new(Mutex::new(vec![]));
+    let discovery = Discovery::new(Some(Box::new(DeviceEvents { events: events.clone() }))).unwrap();
+    let device_registry = discovery.get_device_registry();
+    discovery.set_device_ttl(1);
+    discovery.start(vec![]);
+
+    device_registry.update(DeviceConnectionInfo {
+        device: Some(Device {
+            id: FOREIGN_DEVICE_ID.to_string(),
+            name: "Discovery-Test Advertiser".to_string(),
+            device_type: 0
+        }),
+        tcp: None,
+        ble: None,
+        identity_public_key: vec![]
+    }, DiscoveryMethod::BLE);
+
+    drop(discovery);
+
+    sleep(Duration::from_secs(3));
+    assert_eq!(device_registry.get_devices().len(), 1);
+    assert!(events.lock().unwrap().is_empty());
+}
+
+#[test]
+fn registries_are_isolated() {
+    let mut first_discovery = Discovery::new(None).unwrap();
+    let second_discovery = Discovery::new(None).unwrap();
+
+    first_discovery.parse_discovery_message(discovery_message(), None);
+    second_discovery.start(vec![]);
+
+    assert_eq!(first_discovery.get_devices().len(), 1);
+    assert!(second_discovery.get_devices().is_empty());
+
+    let shared_registry = Arc::new(DeviceRegistry::new());
+    let mut third_discovery = Discovery::with_device_registry(shared_registry.clone(), None).unwrap();
+    third_discovery.parse_discovery_message(discovery_message(), None);
+
+    assert_eq!(shared_registry.get_devices().len(), 1);
+    assert!(Arc::ptr_eq(&shared_registry, &third_discovery.get_device_registry()));
+
+    // Starting another discovery on the same registry keeps the devices, and reports them to its delegate
+    let events = Arc::new(Mutex::new(vec![]));
+    let fourth_discovery = Discovery::with_device_registry(shared_registry.clone(), Some(Box::new(DeviceEvents { events: events.clone() }))).unwrap();
+    fourth_discovery.start(vec![]);
+
+    assert_eq!(shared_registry.get_devices().len(), 1);
+    assert_eq!(*events.lock().unwrap(), vec![format!("added {}", FOREIGN_DEVICE_ID)]);
+
+    second_discovery.stop();
+    fourth_discovery.stop();
+}
+
+#[test]
+fn sending_requires_device_registry() {
+    let storage = std::env::temp_dir().join(format!("data_rct_no_registry_{}", std::process::id()));
+    let storage = storage.to_str().expect("Invalid temp path").to_string();
+
+    let server = NearbyServer::new(Device {
+        id: "No-Registry-Test Sender".to_string(),
+        name: "No-Registry-Test Sender".to_string(),
+        device_type: 0
+    }, storage.clone(), storage.clone(), Some(Box::new(IgnoreRequests)));
+
+    let receiver = Device {
         id: FOREIGN_DEVICE_ID.to_string(),
         name: "Discovery-Test Advertiser".to_string(),
-        port: 52,
-        device_type: "computer".to_string(),
-        ip_address: "2.3.4.5".to_string()
-    }, method, None).unwrap();
+        device_type: 0
+    };
 
-    discovery.advertise();
+    let result = futures::executor::block_on(server.send_clipboard(receiver, vec![ClipboardItem::text("Hello")], None));
+    assert!(matches!(result, Err(ConnectErrors::NoDeviceRegistry)), "{:?}", result);
 
-    return discovery;
+    let _ = std::fs::remove_dir_all(storage);
 }
 
-fn discover_device(method: DiscoveryMethod) {
-    let discovery = Discovery::new(get_my_device(), method.clone(), None).unwrap();
-    discovery.start_search();
+#[test]
+fn advertised_identity_keys_are_required() {
+    let device_registry = DeviceRegistry::new();
+    let connection_info = DeviceConnectionInfo {
+        device: Some(Device {
+            id: FOREIGN_DEVICE_ID.to_string(),
+            name: "Discovery-Test Advertiser".to_string(),
+            device_type: 0
+        }),
+        tcp: None,
+        ble: None,
+        identity_public_key: vec![]
+    };
 
-    let start = Instant::now();
+    assert_eq!(device_registry.matches_identity(FOREIGN_DEVICE_ID, &[1; 32]), None);
 
-    loop {
-        let devices = discovery.get_devices();
+    // Older versions advertise no key over BLE
+    device_registry.update(connection_info.clone(), DiscoveryMethod::BLE);
+    assert_eq!(device_registry.matches_identity(FOREIGN_DEVICE_ID, &[1; 32]), Some(true));
 
-        for device in devices {
-            if device.id == FOREIGN_DEVICE_ID {
-                discovery.stop_search();
-                discovery.stop_advertising();
-                discovery.stop().expect("Failed to stop discovery");
+    device_registry.update(connection_info.clone(), DiscoveryMethod::Udp);
+    assert_eq!(device_registry.matches_identity(FOREIGN_DEVICE_ID, &[1; 32]), Some(false));
 
-                return;
-            }
-        }
+    device_registry.update(DeviceConnectionInfo { identity_public_key: vec![1; 32], ..connection_info.clone() }, DiscoveryMethod::Mdns);
+    assert_eq!(device_registry.matches_identity(FOREIGN_DEVICE_ID, &[1; 32]), Some(true));
+    assert_eq!(device_registry.matches_identity(FOREIGN_DEVICE_ID, &[2; 32]), Some(false));
 
-        if start.elapsed() >= Duration::from_secs(20) {
-            assert!(false, "No devices were found in 20s, mode: {}", method.to_string());
-        }
-    }
+    // An advertisement without a key does not remove the known one
+    device_registry.update(connection_info, DiscoveryMethod::BLE);
+    assert_eq!(device_registry.matches_identity(FOREIGN_DEVICE_ID, &[2; 32]), Some(false));
+}
+
+#[test]
+fn goodbyes_only_remove_devices_seen_over_the_same_medium() {
+    let device_registry = DeviceRegistry::new();
+    let connection_info = DeviceConnectionInfo {
+        device: Some(Device {
+            id: FOREIGN_DEVICE_ID.to_string(),
+            name: "Discovery-Test Advertiser".to_string(),
+            device_type: 0
+        }),
+        tcp: None,
+        ble: None,
+        identity_public_key: vec![1; 32]
+    };
+
+    device_registry.update(connection_info.clone(), DiscoveryMethod::Udp);
+    device_registry.update(connection_info, DiscoveryMethod::Mdns);
+
+    // Still advertised over mDNS, e.g. after the UDP announcements were filtered
+    assert!(!device_registry.remove(FOREIGN_DEVICE_ID, DiscoveryMethod::Udp));
+    assert_eq!(device_registry.get_devices().len(), 1);
+
+    assert!(device_registry.remove(FOREIGN_DEVICE_ID, DiscoveryMethod::Mdns));
+    assert!(device_registry.get_devices().is_empty());
+    assert!(!device_registry.remove(FOREIGN_DEVICE_ID, DiscoveryMethod::Mdns));
 }
 
 #[test]
 fn discovery() {
-    let foreign_discovery = setup_foreign_discovery(DiscoveryMethod::Both);
-    discover_device(DiscoveryMethod::Both);
-    // sleep(Duration::from_secs(20));
-    // loop {}
-    foreign_discovery.stop().expect("Failed to stop foreign discovery");
+    let storage = std::env::temp_dir().join(format!("data_rct_discovery_{}", std::process::id()));
+    let storage = storage.to_str().expect("Invalid temp path").to_string();
+
+    let foreign_server = NearbyServer::new(Device {
+        id: FOREIGN_DEVICE_ID.to_string(),
+        name: "Discovery-Test Advertiser".to_string(),
+        device_type: 0
+    }, storage.clone(), storage.clone(), Some(Box::new(IgnoreRequests)));
+
+    futures::executor::block_on(foreign_server.start());
+
+    let events = Arc::new(Mutex::new(vec![]));
+    let discovery = Discovery::new(Some(Box::new(DeviceEvents { events: events.clone() }))).unwrap();
+    discovery.start(vec![DiscoveryMethod::Mdns, DiscoveryMethod::Udp]);
+
+    assert!(wait_for(&events, |event| *event == format!("added {}", FOREIGN_DEVICE_ID), Duration::from_secs(20)), "No devices were found in 20s");
+
+    foreign_server.stop();
+
+    assert!(wait_for(&events, |event| *event == format!("removed {}", FOREIGN_DEVICE_ID), Duration::from_secs(20)), "The device was not removed after it stopped");
+
+    discovery.stop();
+    let _ = std::fs::remove_dir_all(storage);
 }
@@ -1,12 +1,54 @@
-use std::io::{Read, Write};
-use chacha20::cipher::StreamCipherSeek;
+use std::io::{ErrorKind, Read, Write};
 use rand_core::{OsRng, RngCore};
 use x25519_dalek::{EphemeralSecret, PublicKey};
-use data_rct::encryption::{EncryptedStream, generate_key, generate_iv};
+use data_rct::encryption::{EncryptedStream, SessionKeys, generate_key, generate_iv};
 use crate::helper::MemoryStream;
 
 mod helper;
 
+/// Returns the keys of the writing side and the matching keys of the reading side.
+fn session_keys() -> (SessionKeys, SessionKeys) {
+    let writer_key = generate_key();
+    let writer_iv = generate_iv();
+    let reader_key = generate_key();
+    let reader_iv = generate_iv();
+
+    let writer_keys = SessionKeys {
+        send_key: writer_key,
+        send_iv: writer_iv,
+        receive_key: reader_key,
+        receive_iv: reader_iv
+    };
+
+    let reader_keys = SessionKeys {
+        send_key: reader_key,
+        send_iv: reader_iv,
+        receive_key: writer_key,
+        receive_iv: writer_iv
+    };
+
+    return (writer_keys, reader_keys);
+}
+
+fn read_raw_bytes(encrypted_stream: &mut EncryptedStream<MemoryStream>) -> Vec<u8> {
+    encrypted_stream.raw_stream.set_position(0);
+
+    let mut raw_bytes = Vec::new();
+    encrypted_stream.raw_stream.read_to_end(&mut raw_bytes)
+        .expect("Error reading memory_stream");
+
+    return raw_bytes;
+}
+
+fn memory_stream_with(data: &[u8]) -> MemoryStream {
+    let mut memory_stream = MemoryStream::new();
+    memory_stream.write_all(data)
+        .expect("Failed to write memory_stream");
+    memory_stream.set_position(0);
+
+    return memory_stream;
+}
+
 #[test]
 pub fn diffie_hellman() {
     let alice_secret = EphemeralSecret::random_from_rng(OsRng);
@@ -25,11 +67,9 @@ pub fn diffie_hellman() {
 
 #[test]
 pub fn stream_encryption() {
-    let key = generate_key();
-    let nonce = generate_iv();
+    let (writer_keys, reader_keys) = session_keys();
 
-    let memory_stream = MemoryStream::new();
-    let mut encrypted_stream = EncryptedStream::new(key, nonce, memory_stream);
+    let mut encrypted_stream = EncryptedStream::new(writer_keys, MemoryStream::new());
 
     let write_data = &vec![1, 2, 3];
 
@@ -38,39 +78,25 @@ pub fn stream_encryption() {
 
     assert_eq!(written_bytes, 3);
 
-    encrypted_stream.raw_stream.set_position(0);
-    encrypted_stream.cipher.seek(0);
+    encrypted_stream.finish().expect("Failed to finish EncryptedStream");
 
-    let mut encrypted_gibberish = Vec::new();
-    encrypted_stream.raw_stream.read_to_end(&mut encrypted_gibberish)
-        .expect("Error reading memory_stream");
+    let encrypted_gibberish = read_raw_bytes(&mut encrypted_stream);
+    assert!(!encrypted_gibberish.windows(3).any(|window| window == write_data.as_slice()));
 
-    assert_ne!(write_data, &encrypted_gibberish);
+    let mut decrypting_stream = EncryptedStream::new(reader_keys, memory_stream_with(&encrypted_gibberish));
 
-    let write_data = &vec![1, 2, 3];
-
-    encrypted_stream.write(write_data)
-        .expect("Something went wrong, while trying to write to EncryptedStream");
-
-    encrypted_stream.raw_stream.set_position(0);
-    encrypted_stream.cipher.seek(0);
-
-
-    let mut decrypted = [0u8; 3];
-    encrypted_stream.read(&mut decrypted)
+    let mut decrypted = Vec::new();
+    decrypting_stream.read_to_end(&mut decrypted)
         .expect("Error decrypting memory_stream");
 
     assert_eq!(write_data, &decrypted);
 }
 
-
 #[test]
 pub fn large_stream_encryption() {
-    let key = generate_key();
-    let nonce = generate_iv();
+    let (writer_keys, reader_keys) = session_keys();
 
-    let memory_stream = MemoryStream::new();
-    let mut encrypted_stream = EncryptedStream::new(key, nonce, Box::new(memory_stream));
+    let mut encrypted_stream = EncryptedStream::new(writer_keys, MemoryStream::new());
 
     let mut write_data: [u8; 100000] = [0; 100000];
     let rng = &mut OsRng;
@@ -78,24 +104,72 @@ pub fn large_stream_encryption() {
 
     let write_data = write_data.as_slice();
 
-    let written_bytes = encrypted_stream.write(write_data)
+    encrypted_stream.write_all(write_data)
         .expect("Something went wrong, while trying to write to EncryptedStream");
+    encrypted_stream.finish().expect("Failed to finish EncryptedStream");
 
-    assert!(written_bytes > 0);
-    encrypted_stream.raw_stream.set_position(0);
-    encrypted_stream.cipher.seek(0);
+    let encrypted_gibberish = read_raw_bytes(&mut encrypted_stream);
+    assert_ne!(write_data, &encrypted_gibberish[..write_data.len()]);
 
-    let mut encrypted_gibberish = Vec::new();
-    encrypted_stream.raw_stream.read_to_end(&mut encrypted_gibberish)
-        .expect("Error reading memory_stream");
+    let mut decrypting_stream = EncryptedStream::new(reader_keys, memory_stream_with(&encrypted_gibberish));
 
-    assert_ne!(write_data, &encrypted_gibberish);
-    encrypted_stream.raw_stream.set_position(0);
-    encrypted_stream.cipher.seek(0);
-
-    let mut decrypted_buffer: [u8; 100000] = [0; 100000];
-    let read_bytes = encrypted_stream.read(&mut decrypted_buffer)
+    let mut decrypted = Vec::new();
+    decrypting_stream.read_to_end(&mut decrypted)
         .expect("Something went wrong, while trying to decrypt the stream");
 
-    assert_eq!(write_data, &decrypted_buffer[..read_bytes]);
+    assert_eq!(write_data, decrypted.as_slice());
+}
+
+#[test]
+pub fn tampered_stream_is_rejected() {
+    let (writer_keys, reader_keys) = session_keys();
+
+    let mut encrypted_stream = EncryptedStream::new(writer_keys, MemoryStream::new());
+    encrypted_stream.write_all(&[7u8; 64]).expect("Failed to write to EncryptedStream");
+    encrypted_stream.finish().expect("Failed to finish EncryptedStream");
+
+    let mut encrypted_gibberish = read_raw_bytes(&mut encrypted_stream);
+    encrypted_gibberish[10] ^= 0x01;
+
+    let mut decrypting_stream = EncryptedStream::new(reader_keys, memory_stream_with(&encrypted_gibberish));
+
+    let mut buffer = [0u8; 64];
+    let error = decrypting_stream.read(&mut buffer)
+        .expect_err("Tampered frame was not detected");
+
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+pub fn truncated_stream_is_rejected() {
+    let (writer_keys, reader_keys) = session_keys();
+
+    let mut encrypted_stream = EncryptedStream::new(writer_keys, MemoryStream::new());
+    encrypted_stream.write_all(&[7u8; 64]).expect("Failed to write to EncryptedStream");
+
+    // The last frame is never written
+    let encrypted_gibberish = read_raw_bytes(&mut encrypted_stream);
+
+    let mut decrypting_stream = EncryptedStream::new(reader_keys, memory_stream_with(&encrypted_gibberish));
+
+    let mut decrypted = Vec::new();
+    let error = decrypting_stream.read_to_end(&mut decrypted)
+        .expect_err("Truncated stream was not detected");
+
+    assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+pub fn directions_use_independent_keys() {
+    let (writer_keys, _reader_keys) = session_keys();
+
+    let mut encrypted_stream = EncryptedStream::new(writer_keys, MemoryStream::new());
+    encrypted_stream.write_all(&[7u8; 64]).expect("Failed to write to EncryptedStream");
+    encrypted_stream.finish().expect("Failed to finish EncryptedStream");
+
+    // A stream must not be able to decrypt its own output, since each direction has its own key
+    encrypted_stream.raw_stream.set_position(0);
+
+    let mut buffer = [0u8; 64];
+    assert!(encrypted_stream.read(&mut buffer).is_err());
 }
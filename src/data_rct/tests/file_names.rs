@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::PathBuf;
+use data_rct::file_names::{resolve_local_paths, sanitize_file_name, sanitize_path, FileCollisionStrategy};
+use crate::helper::file_storage;
+
+mod helper;
+
+#[test]
+pub fn file_names_are_sanitized() {
+    assert_eq!(sanitize_file_name("report.pdf"), "report.pdf");
+    assert_eq!(sanitize_file_name("..\\..\\.bashrc"), ".._.._.bashrc");
+    assert_eq!(sanitize_file_name("a:b*c?.txt"), "a_b_c_.txt");
+    assert_eq!(sanitize_file_name("bell\u{7}\n.txt"), "bell.txt");
+    assert_eq!(sanitize_file_name("CON"), "_CON");
+    assert_eq!(sanitize_file_name("lpt1.txt"), "_lpt1.txt");
+    assert_eq!(sanitize_file_name("notes. . "), "notes");
+    assert_eq!(sanitize_file_name(".."), "_");
+    assert_eq!(sanitize_file_name(&"ä".repeat(200)).len(), 254);
+
+    assert_eq!(sanitize_path("photos//./aux/img.png"), "photos/_aux/img.png");
+    assert_eq!(sanitize_path("/"), "_");
+}
+
+#[test]
+pub fn collisions_are_resolved() {
+    let file_storage = file_storage();
+    fs::write(PathBuf::from(&file_storage).join("a.txt"), b"existing").unwrap();
+    fs::write(PathBuf::from(&file_storage).join("a (1).txt"), b"existing").unwrap();
+    fs::create_dir(PathBuf::from(&file_storage).join("photos")).unwrap();
+
+    let files = vec!["a.txt".to_string(), "photos/img.png".to_string(), "b.txt".to_string()];
+    let directories = vec!["photos".to_string(), "photos/empty".to_string()];
+
+    let renamed = resolve_local_paths(&file_storage, &files, &directories, FileCollisionStrategy::Rename).unwrap();
+    assert_eq!(renamed.files, vec!["a (2).txt", "photos (1)/img.png", "b.txt"]);
+    assert_eq!(renamed.directories, vec!["photos (1)", "photos (1)/empty"]);
+
+    let overwritten = resolve_local_paths(&file_storage, &files, &directories, FileCollisionStrategy::Overwrite).unwrap();
+    assert_eq!(overwritten.files, files);
+
+    assert_eq!(resolve_local_paths(&file_storage, &files, &directories, FileCollisionStrategy::Fail), Err("a.txt".to_string()));
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
+
+#[test]
+pub fn duplicate_names_get_their_own_file() {
+    let file_storage = file_storage();
+
+    // a/x.txt and b/x.txt are both sent as x.txt, and "x:y" becomes the same as "x_y"
+    let files = vec!["x.txt".to_string(), "x.txt".to_string(), "x (1).txt".to_string(), "d/x:y".to_string(), "d/x_y".to_string()];
+    let directories = vec!["d".to_string()];
+
+    for strategy in [FileCollisionStrategy::Rename, FileCollisionStrategy::Overwrite, FileCollisionStrategy::Fail] {
+        let local_paths = resolve_local_paths(&file_storage, &files, &directories, strategy).unwrap();
+        assert_eq!(local_paths.files, vec!["x.txt", "x (2).txt", "x (1).txt", "d/x_y", "d/x_y (1)"]);
+    }
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
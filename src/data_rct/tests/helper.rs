@@ -1,14 +1,22 @@
+#![allow(dead_code)]
+
+use std::env::temp_dir;
+use std::fs;
 use std::io::{Cursor, Read, Write};
-use data_rct::stream::{Stream, StreamRead, StreamWrite};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use data_rct::stream::Close;
+use uuid::Uuid;
 
 pub struct MemoryStream {
     last_written_byte_length: usize,
     cursor: Cursor<Vec<u8>>
 }
 
-impl StreamRead for MemoryStream {}
-impl StreamWrite for MemoryStream {}
-impl Stream for MemoryStream {}
+impl Close for MemoryStream {
+    fn close(&self) {}
+}
 
 impl MemoryStream {
     pub fn new() -> Self {
@@ -50,12 +58,34 @@ impl Read for MemoryStream {
     }
 }
 
+/// Creates an empty directory for a test, so parallel tests never share files.
+pub fn file_storage() -> String {
+    let file_storage = temp_dir().join(Uuid::new_v4().to_string());
+    fs::create_dir_all(&file_storage).expect("Failed to create file storage");
+
+    return file_storage.to_str().expect("Invalid temp path").to_string();
+}
+
+/// Polls `items` until one of them matches `expected`, or gives up after `timeout`.
+pub fn wait_for<T>(items: &Arc<Mutex<Vec<T>>>, expected: impl Fn(&T) -> bool, timeout: Duration) -> bool {
+    let start = Instant::now();
+
+    while start.elapsed() < timeout {
+        if items.lock().unwrap().iter().any(&expected) {
+            return true;
+        }
+
+        sleep(Duration::from_millis(50));
+    }
+
+    return false;
+}
 
 #[test]
 pub fn memory_stream() {
     let mut memory_stream = MemoryStream::new();
 
-    memory_stream.write(&[4u8, 5u8, 6u8])
+    memory_stream.write_all(&[4u8, 5u8, 6u8])
         .expect("Failed to write memory_stream");
 
     memory_stream.set_position(0);
@@ -68,7 +98,7 @@ pub fn memory_stream() {
 
     // ====
 
-    memory_stream.write(&[2u8, 7u8, 9u8])
+    memory_stream.write_all(&[2u8, 7u8, 9u8])
         .expect("Failed to write memory_stream");
     memory_stream.set_position(memory_stream.position() - memory_stream.last_written_byte_length as u64);
 
@@ -80,10 +110,10 @@ pub fn memory_stream() {
 
     // ====
 
-    memory_stream.write(&[2u8, 7u8, 9u8])
+    memory_stream.write_all(&[2u8, 7u8, 9u8])
         .expect("Failed to write memory_stream");
 
-    memory_stream.write(&[1u8, 2u8, 0u8])
+    memory_stream.write_all(&[1u8, 2u8, 0u8])
         .expect("Failed to write memory_stream");
 
     memory_stream.set_position(memory_stream.position() - 6);
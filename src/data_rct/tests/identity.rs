@@ -0,0 +1,26 @@
+use std::env::temp_dir;
+use std::fs;
+use data_rct::identity::DeviceIdentity;
+use uuid::Uuid;
+
+#[test]
+pub fn identity_is_persisted() {
+    let config_storage = temp_dir().join(Uuid::new_v4().to_string());
+    let config_storage = config_storage.to_str().expect("Invalid temp path");
+
+    let identity = DeviceIdentity::load_or_generate(config_storage).expect("Failed to generate identity");
+    let loaded_identity = DeviceIdentity::load_or_generate(config_storage).expect("Failed to load identity");
+
+    assert_eq!(identity.public_key(), loaded_identity.public_key());
+    assert_eq!(identity.fingerprint(), loaded_identity.fingerprint());
+    assert_ne!(identity.public_key(), DeviceIdentity::generate().public_key());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = fs::metadata(std::path::Path::new(config_storage).join("identity.key")).expect("Identity was not stored");
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    fs::remove_dir_all(config_storage).expect("Failed to clean up");
+}
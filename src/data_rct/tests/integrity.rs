@@ -0,0 +1,27 @@
+use data_rct::integrity::ContentHasher;
+
+#[test]
+pub fn resumed_content_hashes_like_a_single_pass() {
+    let content: Vec<u8> = (0..10_000u32).map(|index| (index % 251) as u8).collect();
+
+    let mut sender_hasher = ContentHasher::new();
+    sender_hasher.update(&content);
+
+    let mut receiver_hasher = ContentHasher::new();
+    receiver_hasher.update_from_reader(&mut &content[..4_000], 4_000).expect("Failed to hash prefix");
+    receiver_hasher.update(&content[4_000..]);
+
+    assert!(receiver_hasher.verify(&sender_hasher.trailer()));
+}
+
+#[test]
+pub fn detects_modified_content() {
+    let mut sender_hasher = ContentHasher::new();
+    sender_hasher.update(b"original content");
+
+    let mut receiver_hasher = ContentHasher::new();
+    receiver_hasher.update(b"modified content");
+
+    assert!(!receiver_hasher.verify(&sender_hasher.trailer()));
+    assert!(ContentHasher::new().update_from_reader(&mut &b"short"[..], 10).is_err());
+}
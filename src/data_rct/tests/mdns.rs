@@ -0,0 +1,57 @@
+use data_rct::mdns::{device_id, to_device_connection_info, to_service_info, MDNS_SERVICE_TYPE};
+use data_rct::protocol::discovery::{BluetoothLeConnectionInfo, Device, DeviceConnectionInfo, TcpConnectionInfo};
+use data_rct::protocol::discovery::device::DeviceType;
+
+fn device_connection_info() -> DeviceConnectionInfo {
+    return DeviceConnectionInfo {
+        device: Some(Device {
+            id: "B53CCB62-7DAB-4403-9FEB-F336834DB41F".to_string(),
+            name: "Rust Device".to_string(),
+            device_type: DeviceType::Desktop as i32
+        }),
+        tcp: Some(TcpConnectionInfo {
+            hostname: "192.168.1.42".to_string(),
+            port: 8080
+        }),
+        ble: Some(BluetoothLeConnectionInfo {
+            uuid: "68D60EB2-8AAA-4D72-8851-BD6D64E169B7".to_string(),
+            psm: 42
+        }),
+        identity_public_key: (0..32).collect()
+    };
+}
+
+#[test]
+pub fn service_info_carries_connection_info() {
+    let device_connection_info = device_connection_info();
+    let service_info = to_service_info(&device_connection_info).expect("Failed to create service info");
+
+    assert_eq!(service_info.get_fullname(), format!("B53CCB62-7DAB-4403-9FEB-F336834DB41F.{}", MDNS_SERVICE_TYPE));
+    assert_eq!(device_id(service_info.get_fullname()), Some("B53CCB62-7DAB-4403-9FEB-F336834DB41F".to_string()));
+
+    let received = to_device_connection_info(&service_info).expect("Failed to read service info");
+
+    assert_eq!(received.device, device_connection_info.device);
+    assert_eq!(received.tcp, device_connection_info.tcp);
+    assert_eq!(received.identity_public_key, device_connection_info.identity_public_key);
+    assert_eq!(received.ble, None);
+}
+
+#[test]
+pub fn devices_without_tcp_are_not_advertised() {
+    let mut device_connection_info = device_connection_info();
+    device_connection_info.tcp = None;
+
+    assert!(to_service_info(&device_connection_info).is_none());
+    assert_eq!(device_id("printer._ipp._tcp.local."), None);
+}
+
+#[test]
+pub fn services_without_identity_key_are_ignored() {
+    let mut device_connection_info = device_connection_info();
+    device_connection_info.identity_public_key = vec![];
+
+    let service_info = to_service_info(&device_connection_info).expect("Failed to create service info");
+
+    assert!(to_device_connection_info(&service_info).is_none());
+}
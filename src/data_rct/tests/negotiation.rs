@@ -0,0 +1,43 @@
+use data_rct::errors::IncomingErrors;
+use data_rct::negotiation::{local_message_header, negotiate_protocol};
+use data_rct::protocol::communication::{Capability, MessageHeader};
+use data_rct::protocol::communication::message_header::MessageTypes;
+
+fn header(protocol_version: i32, min_protocol_version: i32, capabilities: &[Capability]) -> MessageHeader {
+    return MessageHeader {
+        protocol_version,
+        r#type: 0,
+        capabilities: capabilities.iter().map(|capability| *capability as i32).collect(),
+        min_protocol_version
+    };
+}
+
+#[test]
+pub fn downgrades_to_common_version_and_capabilities() {
+    let local_header = header(3, 1, &[Capability::Resumption, Capability::MultiFile]);
+    let remote_header = header(2, 2, &[Capability::MultiFile, Capability::Clipboard]);
+
+    let negotiated_protocol = negotiate_protocol(&local_header, &remote_header).expect("Negotiation failed");
+
+    assert_eq!(negotiated_protocol.protocol_version, 2);
+    assert_eq!(negotiated_protocol.capabilities, vec![Capability::MultiFile]);
+    assert!(negotiated_protocol.supports(Capability::MultiFile));
+    assert!(!negotiated_protocol.supports(Capability::Resumption));
+}
+
+#[test]
+pub fn refuses_incompatible_versions() {
+    let local_header = header(3, 3, &[]);
+
+    assert!(matches!(negotiate_protocol(&local_header, &header(2, 1, &[])), Err(IncomingErrors::InvalidVersion)));
+    assert!(matches!(negotiate_protocol(&header(1, 1, &[]), &header(4, 2, &[])), Err(IncomingErrors::InvalidVersion)));
+    assert!(matches!(negotiate_protocol(&local_header, &header(0, 0, &[])), Err(IncomingErrors::MissingProtocolVersion)));
+}
+
+#[test]
+pub fn refuses_versions_without_content_acknowledgements() {
+    let local_header = local_message_header(MessageTypes::KeyExchangeResponse);
+
+    assert!(matches!(negotiate_protocol(&local_header, &header(3, 1, &[])), Err(IncomingErrors::InvalidVersion)));
+    assert!(negotiate_protocol(&local_header, &header(4, 4, &[])).is_ok());
+}
@@ -0,0 +1,59 @@
+use std::env::temp_dir;
+use std::fs;
+use data_rct::Device;
+use data_rct::pairing::{TrustState, TrustStore};
+use uuid::Uuid;
+
+fn device() -> Device {
+    return Device {
+        id: Uuid::new_v4().to_string(),
+        name: "Test Device".to_string(),
+        device_type: 0
+    };
+}
+
+#[test]
+pub fn trust_on_first_use() {
+    let mut trust_store = TrustStore::in_memory();
+    let device = device();
+
+    assert_eq!(trust_store.check_device(&device, &[1; 32]), TrustState::Unknown);
+    assert_eq!(trust_store.check_device(&device, &[1; 32]), TrustState::Unknown);
+    assert_eq!(trust_store.check_device(&device, &[2; 32]), TrustState::KeyChanged);
+
+    // Unpaired devices are not listed
+    assert!(trust_store.get_devices().is_empty());
+
+    trust_store.pair(&device, &[1; 32]).unwrap();
+
+    assert_eq!(trust_store.check_device(&device, &[1; 32]), TrustState::Paired);
+    assert_eq!(trust_store.check_device(&device, &[2; 32]), TrustState::KeyChanged);
+
+    trust_store.forget(&device.id).unwrap();
+
+    assert!(trust_store.get_devices().is_empty());
+    assert_eq!(trust_store.check_device(&device, &[2; 32]), TrustState::Unknown);
+}
+
+#[test]
+pub fn trusted_devices_are_persisted() {
+    let config_storage = temp_dir().join(Uuid::new_v4().to_string());
+    let config_storage = config_storage.to_str().expect("Invalid temp path");
+
+    let paired_device = device();
+    let unknown_device = device();
+
+    let mut trust_store = TrustStore::load(config_storage).expect("Failed to create trust store");
+    trust_store.pair(&paired_device, &[1; 32]).unwrap();
+    trust_store.check_device(&unknown_device, &[2; 32]);
+    trust_store.pair(&device(), &[3; 32]).unwrap();
+
+    let loaded_trust_store = TrustStore::load(config_storage).expect("Failed to load trust store");
+
+    // Only paired devices are stored
+    assert_eq!(loaded_trust_store.get_devices().len(), 2);
+    assert_eq!(loaded_trust_store.get_trust_state(&paired_device.id, &[1; 32]), TrustState::Paired);
+    assert_eq!(loaded_trust_store.get_trust_state(&unknown_device.id, &[3; 32]), TrustState::Unknown);
+
+    fs::remove_dir_all(config_storage).expect("Failed to clean up");
+}
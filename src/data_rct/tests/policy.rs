@@ -0,0 +1,98 @@
+use std::time::{Duration, UNIX_EPOCH};
+use data_rct::nearby::ConnectionIntentType;
+use data_rct::pairing::TrustState;
+use data_rct::policy::{AutoAcceptPolicy, PolicyDecision, PolicyRule, RequestDetails, TimeWindow};
+
+fn rule(decision: PolicyDecision) -> PolicyRule {
+    return PolicyRule {
+        sender_id: None,
+        trusted_only: false,
+        intent_type: None,
+        max_size: None,
+        file_extensions: vec![],
+        time_window: None,
+        decision
+    };
+}
+
+fn clipboard_from_laptop() -> RequestDetails {
+    return RequestDetails {
+        sender_id: "laptop".to_string(),
+        trust_state: TrustState::Paired,
+        intent_type: ConnectionIntentType::Clipboard,
+        size: 42,
+        file_names: vec![]
+    };
+}
+
+#[test]
+pub fn first_matching_rule_decides() {
+    let now = UNIX_EPOCH + Duration::from_secs(12 * 60 * 60);
+
+    let policy = AutoAcceptPolicy::new(vec![
+        PolicyRule { sender_id: Some("laptop".to_string()), trusted_only: true, intent_type: Some(ConnectionIntentType::Clipboard), ..rule(PolicyDecision::Accept) },
+        PolicyRule { file_extensions: vec!["exe".to_string()], ..rule(PolicyDecision::Decline) }
+    ]);
+
+    assert_eq!(policy.decide(&clipboard_from_laptop(), now), PolicyDecision::Accept);
+
+    let key_changed = RequestDetails { trust_state: TrustState::KeyChanged, ..clipboard_from_laptop() };
+    assert_eq!(policy.decide(&key_changed, now), PolicyDecision::Ask);
+
+    let executable = RequestDetails {
+        intent_type: ConnectionIntentType::FileTransfer,
+        file_names: vec!["photos/a.JPG".to_string(), "setup.EXE".to_string()],
+        ..clipboard_from_laptop()
+    };
+    assert_eq!(policy.decide(&executable, now), PolicyDecision::Ask);
+
+    let only_executables = RequestDetails { file_names: vec!["setup.EXE".to_string()], ..executable };
+    assert_eq!(policy.decide(&only_executables, now), PolicyDecision::Decline);
+
+    assert_eq!(AutoAcceptPolicy::default().decide(&clipboard_from_laptop(), now), PolicyDecision::Ask);
+}
+
+#[test]
+pub fn unverified_senders_are_not_accepted() {
+    let now = UNIX_EPOCH;
+
+    // The device id alone can be claimed by any device
+    let by_sender = AutoAcceptPolicy::new(vec![
+        PolicyRule { sender_id: Some("laptop".to_string()), ..rule(PolicyDecision::Accept) }
+    ]);
+
+    assert_eq!(by_sender.decide(&clipboard_from_laptop(), now), PolicyDecision::Accept);
+
+    let unpaired = RequestDetails { trust_state: TrustState::Unknown, ..clipboard_from_laptop() };
+    assert_eq!(by_sender.decide(&unpaired, now), PolicyDecision::Ask);
+
+    let accept_all = AutoAcceptPolicy::new(vec![
+        rule(PolicyDecision::Accept),
+        rule(PolicyDecision::Decline)
+    ]);
+
+    assert_eq!(accept_all.decide(&clipboard_from_laptop(), now), PolicyDecision::Accept);
+    assert_eq!(accept_all.decide(&unpaired, now), PolicyDecision::Decline);
+
+    let key_changed = RequestDetails { trust_state: TrustState::KeyChanged, ..clipboard_from_laptop() };
+    assert_eq!(accept_all.decide(&key_changed, now), PolicyDecision::Decline);
+}
+
+#[test]
+pub fn size_and_time_window_are_checked() {
+    let night = TimeWindow { start_minute: 22 * 60, end_minute: 7 * 60, utc_offset_minutes: 60 };
+    let policy = AutoAcceptPolicy::new(vec![
+        PolicyRule { max_size: Some(100), time_window: Some(night), ..rule(PolicyDecision::Accept) }
+    ]);
+
+    // 22:30 UTC is 23:30 local time
+    let late = UNIX_EPOCH + Duration::from_secs((22 * 60 + 30) * 60);
+    // 06:30 UTC is 07:30 local time
+    let morning = UNIX_EPOCH + Duration::from_secs((6 * 60 + 30) * 60);
+
+    assert_eq!(policy.decide(&clipboard_from_laptop(), late), PolicyDecision::Accept);
+    assert_eq!(policy.decide(&clipboard_from_laptop(), morning), PolicyDecision::Ask);
+
+    let large = RequestDetails { size: 101, ..clipboard_from_laptop() };
+    assert_eq!(policy.decide(&large, late), PolicyDecision::Ask);
+}
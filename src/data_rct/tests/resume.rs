@@ -0,0 +1,162 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use data_rct::protocol::communication::FileManifestEntry;
+use data_rct::protocol::resume::ResumeState;
+use data_rct::resume::{load_state, partial_file_path, remove_stale_partial_files, remove_state, resume_offset, save_state, storage_id, transfer_id, RESUME_STATE_MAX_AGE, STALE_PARTIAL_FILE_AGE};
+use crate::helper::file_storage;
+
+mod helper;
+
+fn manifest() -> Vec<FileManifestEntry> {
+    return vec![
+        FileManifestEntry { file_name: "a.bin".to_string(), file_size: 10, modified: 1 },
+        FileManifestEntry { file_name: "b.bin".to_string(), file_size: 20, modified: 2 }
+    ];
+}
+
+#[test]
+pub fn resumes_from_verified_offset() {
+    let file_storage = file_storage();
+    let transfer_id = transfer_id("receiver", &[PathBuf::from("a.bin"), PathBuf::from("b.bin")]);
+    let storage_id = storage_id(&transfer_id, &[1; 32]).unwrap();
+
+    fs::write(partial_file_path(&file_storage, &storage_id, 0), [0u8; 10]).unwrap();
+    fs::write(partial_file_path(&file_storage, &storage_id, 1), [0u8; 5]).unwrap();
+
+    save_state(&file_storage, &ResumeState {
+        transfer_id: transfer_id.clone(),
+        sender_identity_public_key: vec![1; 32],
+        files: manifest(),
+        offset: 18,
+        local_file_names: vec![],
+        local_directories: vec![]
+    }).expect("Failed to save resume state");
+
+    // Only 5 bytes of b.bin made it to disk
+    assert_eq!(resume_offset(&file_storage, &transfer_id, &[1; 32], &manifest()), 15);
+
+    // Another sender, using the same transfer id, gets its own files and leaves the state alone
+    assert_ne!(storage_id, data_rct::resume::storage_id(&transfer_id, &[2; 32]).unwrap());
+    assert_eq!(resume_offset(&file_storage, &transfer_id, &[2; 32], &manifest()), 0);
+    assert_eq!(resume_offset(&file_storage, &transfer_id, &[1; 32], &manifest()), 15);
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
+
+#[test]
+pub fn changed_source_is_not_resumed() {
+    let file_storage = file_storage();
+    let transfer_id = transfer_id("receiver", &[PathBuf::from("a.bin"), PathBuf::from("b.bin")]);
+    let storage_id = storage_id(&transfer_id, &[1; 32]).unwrap();
+
+    fs::write(partial_file_path(&file_storage, &storage_id, 0), [0u8; 10]).unwrap();
+
+    save_state(&file_storage, &ResumeState {
+        transfer_id: transfer_id.clone(),
+        sender_identity_public_key: vec![1; 32],
+        files: manifest(),
+        offset: 10,
+        local_file_names: vec![],
+        local_directories: vec![]
+    }).expect("Failed to save resume state");
+
+    let mut changed_manifest = manifest();
+    changed_manifest[1].modified = 3;
+
+    assert_eq!(resume_offset(&file_storage, &transfer_id, &[1; 32], &changed_manifest), 0);
+
+    // The outdated state is removed
+    assert_eq!(resume_offset(&file_storage, &transfer_id, &[1; 32], &manifest()), 0);
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
+
+#[test]
+pub fn invalid_transfer_ids_are_rejected() {
+    let file_storage = file_storage();
+
+    let state = ResumeState {
+        transfer_id: "../escape".to_string(),
+        sender_identity_public_key: vec![],
+        files: vec![],
+        offset: 0,
+        local_file_names: vec![],
+        local_directories: vec![]
+    };
+
+    assert!(save_state(&file_storage, &state).is_err());
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
+
+#[test]
+pub fn stale_partial_files_are_removed() {
+    let file_storage = file_storage();
+    let transfer_id = transfer_id("receiver", &[PathBuf::from("a.bin"), PathBuf::from("b.bin")]);
+    let storage_id = storage_id(&transfer_id, &[1; 32]).unwrap();
+    let partial_file = partial_file_path(&file_storage, &storage_id, 0);
+
+    fs::write(&partial_file, [0u8; 10]).unwrap();
+    fs::write(PathBuf::from(&file_storage).join("a.bin"), [0u8; 10]).unwrap();
+
+    save_state(&file_storage, &ResumeState {
+        transfer_id: transfer_id.clone(),
+        sender_identity_public_key: vec![1; 32],
+        files: manifest(),
+        offset: 10,
+        local_file_names: vec![],
+        local_directories: vec![]
+    }).expect("Failed to save resume state");
+
+    // Still resumable
+    remove_stale_partial_files(&file_storage);
+    assert!(partial_file.exists());
+
+    // Without state, but possibly still being written to
+    remove_state(&file_storage, &storage_id);
+    remove_stale_partial_files(&file_storage);
+    assert!(partial_file.exists());
+
+    set_age(&partial_file, STALE_PARTIAL_FILE_AGE + Duration::from_secs(60));
+    remove_stale_partial_files(&file_storage);
+    assert!(!partial_file.exists());
+    assert!(PathBuf::from(&file_storage).join("a.bin").exists());
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
+
+#[test]
+pub fn resume_states_expire() {
+    let file_storage = file_storage();
+    let transfer_id = transfer_id("receiver", &[PathBuf::from("a.bin"), PathBuf::from("b.bin")]);
+    let storage_id = storage_id(&transfer_id, &[1; 32]).unwrap();
+    let partial_file = partial_file_path(&file_storage, &storage_id, 0);
+
+    fs::write(&partial_file, [0u8; 10]).unwrap();
+
+    save_state(&file_storage, &ResumeState {
+        transfer_id: transfer_id.clone(),
+        sender_identity_public_key: vec![1; 32],
+        files: manifest(),
+        offset: 10,
+        local_file_names: vec![],
+        local_directories: vec![]
+    }).expect("Failed to save resume state");
+
+    let state_file = PathBuf::from(&file_storage).join(format!(".{}.resume", storage_id));
+    set_age(&state_file, RESUME_STATE_MAX_AGE + Duration::from_secs(60));
+    set_age(&partial_file, RESUME_STATE_MAX_AGE + Duration::from_secs(60));
+
+    remove_stale_partial_files(&file_storage);
+
+    assert!(load_state(&file_storage, &storage_id).is_none());
+    assert!(!partial_file.exists());
+
+    fs::remove_dir_all(file_storage).expect("Failed to clean up");
+}
+
+fn set_age(path: &PathBuf, age: Duration) {
+    let file = fs::File::options().write(true).open(path).expect("Failed to open file");
+    file.set_modified(SystemTime::now() - age).expect("Failed to set modification time");
+}
@@ -0,0 +1,800 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use data_rct::clipboard::ClipboardItem;
+use data_rct::connection_request::{ConnectionRequest, ReceiveProgressDelegate, ReceiveProgressState};
+use data_rct::discovery::Discovery;
+use data_rct::errors::ConnectErrors;
+use data_rct::file_names::FileCollisionStrategy;
+use data_rct::nearby::{ConnectionIntentType, NearbyConnectionDelegate, NearbyServer, SendProgressDelegate, SendProgressState};
+use data_rct::protocol::discovery::{Device, DeviceDiscoveryMessage};
+use data_rct::protocol::discovery::device_discovery_message::Content;
+use data_rct::protocol::prost::Message;
+use data_rct::protocol::resume::ResumeState;
+use data_rct::resume::{partial_file_path, save_state, storage_id};
+use data_rct::DeclineReason;
+use crate::helper::{file_storage, wait_for};
+
+mod helper;
+
+type Decide = Arc<dyn Fn(Arc<ConnectionRequest>) + Send + Sync>;
+
+/// Answers every request on its own thread, like a user tapping a button.
+struct ReceivingApp {
+    requests: Arc<Mutex<Vec<Arc<ConnectionRequest>>>>,
+    events: Arc<Mutex<Vec<String>>>,
+    decide: Decide
+}
+
+impl Debug for ReceivingApp {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+        return write!(formatter, "ReceivingApp");
+    }
+}
+
+impl NearbyConnectionDelegate for ReceivingApp {
+    fn received_verification_code(&self, _sender: Device, verification_code: String) {
+        self.events.lock().unwrap().push(format!("code {}", verification_code));
+    }
+
+    fn verification_aborted(&self, _sender: Device) {
+        self.events.lock().unwrap().push("aborted".to_string());
+    }
+
+    fn received_connection_request(&self, request: Arc<ConnectionRequest>) {
+        self.requests.lock().unwrap().push(request.clone());
+        request.set_progress_delegate(Box::new(ReceiveEvents { events: self.events.clone() }));
+
+        let decide = self.decide.clone();
+        thread::spawn(move || decide(request));
+    }
+
+    fn declined_connection_request(&self, _sender: Option<Device>, reason: DeclineReason) {
+        self.events.lock().unwrap().push(format!("declined {:?}", reason));
+    }
+}
+
+#[derive(Debug)]
+struct ReceiveEvents {
+    events: Arc<Mutex<Vec<String>>>
+}
+
+impl ReceiveProgressDelegate for ReceiveEvents {
+    fn progress_changed(&self, progress: ReceiveProgressState) {
+        let event = match progress {
+            ReceiveProgressState::Handshake => "handshake".to_string(),
+            ReceiveProgressState::Receiving { file_index, file_progress, .. } if file_progress >= 1.0 => format!("file {} done", file_index),
+            ReceiveProgressState::ClipboardReceived { items } => format!("clipboard {}", items.iter()
+                .map(|item| format!("{}={}", item.mime_type, item.data.len()))
+                .collect::<Vec<String>>()
+                .join(",")),
+            ReceiveProgressState::Cancelled => "cancelled".to_string(),
+            ReceiveProgressState::Finished => "finished".to_string(),
+            ReceiveProgressState::Expired => "expired".to_string(),
+            _ => return
+        };
+
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[derive(Debug)]
+struct SendEvents {
+    send_id: Mutex<String>,
+    verification_codes: Sender<(String, String)>,
+    transferring: AtomicBool,
+    send_ids: Arc<Mutex<Vec<String>>>,
+    first_progress: Arc<Mutex<Vec<f64>>>,
+    events: Arc<Mutex<Vec<String>>>
+}
+
+impl SendProgressDelegate for SendEvents {
+    fn progress_changed(&self, progress: SendProgressState) {
+        let event = match progress {
+            SendProgressState::Started { send_id } => {
+                *self.send_id.lock().unwrap() = send_id.clone();
+                self.send_ids.lock().unwrap().push(send_id);
+                return;
+            },
+            SendProgressState::VerificationRequired { verification_code } => {
+                let send_id = self.send_id.lock().unwrap().clone();
+                let _ = self.verification_codes.send((verification_code, send_id));
+                return;
+            },
+            SendProgressState::Transferring { progress } => {
+                if !self.transferring.swap(true, Ordering::SeqCst) {
+                    self.first_progress.lock().unwrap().push(progress);
+                }
+
+                return;
+            },
+            SendProgressState::Finished => "finished".to_string(),
+            SendProgressState::Cancelled => "cancelled".to_string(),
+            SendProgressState::CancelledByReceiver => "cancelled by receiver".to_string(),
+            SendProgressState::RequestExpired => "expired".to_string(),
+            SendProgressState::DeclinedWithReason { reason, message } => format!("declined {:?} {:?}", reason, message),
+            _ => return
+        };
+
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+/// A receiver and a sender on the loopback interface. The user of the sender confirms every verification code.
+struct Loopback {
+    directory: PathBuf,
+    inbox: PathBuf,
+    outbox: PathBuf,
+    receiver: NearbyServer,
+    receiver_device: Device,
+    sender: Arc<NearbyServer>,
+    requests: Arc<Mutex<Vec<Arc<ConnectionRequest>>>>,
+    receive_events: Arc<Mutex<Vec<String>>>,
+    send_events: Arc<Mutex<Vec<String>>>,
+    send_ids: Arc<Mutex<Vec<String>>>,
+    first_progress: Arc<Mutex<Vec<f64>>>,
+    confirmed_codes: Arc<Mutex<Vec<String>>>,
+    verification_codes: Sender<(String, String)>
+}
+
+fn device(id: &str) -> Device {
+    return Device {
+        id: id.to_string(),
+        name: id.to_string(),
+        device_type: 0
+    };
+}
+
+fn accept() -> Decide {
+    return Arc::new(|request: Arc<ConnectionRequest>| request.accept());
+}
+
+fn ignore() -> Decide {
+    return Arc::new(|_request: Arc<ConnectionRequest>| {});
+}
+
+impl Loopback {
+    fn new(name: &str, decide: Decide) -> Self {
+        let directory = PathBuf::from(file_storage());
+        let inbox = directory.join("inbox");
+        let outbox = directory.join("outbox");
+        fs::create_dir_all(&inbox).expect("Failed to create inbox");
+        fs::create_dir_all(&outbox).expect("Failed to create outbox");
+
+        let requests = Arc::new(Mutex::new(vec![]));
+        let receive_events = Arc::new(Mutex::new(vec![]));
+        let receiver_device = device(&format!("Transfers-Test Receiver {}", name));
+
+        let receiver = NearbyServer::new(
+            receiver_device.clone(),
+            inbox.to_string_lossy().to_string(),
+            directory.join("receiver").to_string_lossy().to_string(),
+            Some(Box::new(ReceivingApp { requests: requests.clone(), events: receive_events.clone(), decide }))
+        );
+
+        futures::executor::block_on(receiver.start());
+
+        let mut connection_info = futures::executor::block_on(receiver.variables.read()).device_connection_info.clone();
+        connection_info.tcp.as_mut().expect("Receiver has no TCP server").hostname = "127.0.0.1".to_string();
+
+        let mut discovery = Discovery::new(None).expect("Failed to create discovery");
+        discovery.parse_discovery_message(DeviceDiscoveryMessage {
+            content: Some(Content::DeviceConnectionInfo(connection_info))
+        }.encode_length_delimited_to_vec(), None);
+
+        let sender = Arc::new(NearbyServer::new(
+            device(&format!("Transfers-Test Sender {}", name)),
+            outbox.to_string_lossy().to_string(),
+            directory.join("sender").to_string_lossy().to_string(),
+            None
+        ));
+
+        sender.set_device_registry(discovery.get_device_registry());
+
+        let (verification_codes, received_codes) = channel::<(String, String)>();
+        let confirmed_codes = Arc::new(Mutex::new(vec![]));
+        let confirming_sender = sender.clone();
+        let confirmed = confirmed_codes.clone();
+
+        thread::spawn(move || {
+            for (verification_code, send_id) in received_codes {
+                confirmed.lock().unwrap().push(verification_code);
+                thread::sleep(Duration::from_millis(50));
+                confirming_sender.confirm_verification_code(send_id, true);
+            }
+        });
+
+        return Self {
+            directory,
+            inbox,
+            outbox,
+            receiver,
+            receiver_device,
+            sender,
+            requests,
+            receive_events,
+            send_events: Arc::new(Mutex::new(vec![])),
+            send_ids: Arc::new(Mutex::new(vec![])),
+            first_progress: Arc::new(Mutex::new(vec![])),
+            confirmed_codes,
+            verification_codes
+        };
+    }
+
+    /// Reports the verification codes of the sender to `verification_codes` instead of confirming them.
+    fn progress_with_codes(&self, verification_codes: Sender<(String, String)>) -> Option<Box<dyn SendProgressDelegate>> {
+        return Some(Box::new(SendEvents {
+            send_id: Mutex::new(String::new()),
+            verification_codes,
+            transferring: AtomicBool::new(false),
+            send_ids: self.send_ids.clone(),
+            first_progress: self.first_progress.clone(),
+            events: self.send_events.clone()
+        }));
+    }
+
+    fn progress(&self) -> Option<Box<dyn SendProgressDelegate>> {
+        return self.progress_with_codes(self.verification_codes.clone());
+    }
+
+    fn write_outbox(&self, name: &str, size: u32, seed: u32) -> (String, Vec<u8>) {
+        let content: Vec<u8> = (0..size).map(|index| ((index + seed) % 251) as u8).collect();
+        let path = self.outbox.join(name);
+
+        fs::create_dir_all(path.parent().expect("Missing parent directory")).expect("Failed to create directory");
+        fs::write(&path, &content).expect("Failed to write file");
+
+        return (path.to_string_lossy().to_string(), content);
+    }
+
+    fn send_file(&self, path: String, progress: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        return futures::executor::block_on(self.sender.send_file(self.receiver_device.clone(), path, progress));
+    }
+
+    fn send_file_in_background(&self, path: String, progress: Option<Box<dyn SendProgressDelegate>>) -> JoinHandle<Result<(), ConnectErrors>> {
+        let sender = self.sender.clone();
+        let receiver_device = self.receiver_device.clone();
+
+        return thread::spawn(move || futures::executor::block_on(sender.send_file(receiver_device, path, progress)));
+    }
+
+    fn wait_for_send_id(&self) -> String {
+        assert!(wait_for(&self.send_ids, |_| true, Duration::from_secs(5)), "The send did not start");
+        return self.send_ids.lock().unwrap()[0].clone();
+    }
+
+    fn wait_for_partial_content(&self) {
+        let start = Instant::now();
+
+        while self.partial_size() < 1_000_000 {
+            assert!(start.elapsed() < Duration::from_secs(20), "No content arrived");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// The receiver reports its progress after it answered the sender.
+    fn settle(&self) {
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    fn partial_size(&self) -> u64 {
+        return fs::read_dir(&self.inbox).expect("Failed to list inbox")
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".part"))
+            .map(|entry| entry.metadata().map(|metadata| metadata.len()).unwrap_or(0))
+            .sum();
+    }
+
+    fn transfer_events(&self) -> Vec<String> {
+        return self.receive_events.lock().unwrap().iter()
+            .filter(|event| !event.starts_with("code "))
+            .cloned()
+            .collect();
+    }
+
+    fn last_receive_event(&self) -> Option<String> {
+        return self.receive_events.lock().unwrap().last().cloned();
+    }
+
+    fn last_send_event(&self) -> Option<String> {
+        return self.send_events.lock().unwrap().last().cloned();
+    }
+
+    fn request(&self, index: usize) -> Arc<ConnectionRequest> {
+        return self.requests.lock().unwrap()[index].clone();
+    }
+
+    fn receiver_port(&self) -> u16 {
+        let variables = futures::executor::block_on(self.receiver.variables.read());
+        return variables.device_connection_info.tcp.as_ref().expect("Receiver has no TCP server").port as u16;
+    }
+}
+
+impl Drop for Loopback {
+    fn drop(&mut self) {
+        self.receiver.stop();
+        let _ = fs::remove_dir_all(&self.directory);
+    }
+}
+
+#[test]
+pub fn sends_file_and_pairs_devices() {
+    let loopback = Loopback::new("single", accept());
+    let (path, content) = loopback.write_outbox("hello.bin", 300_000, 0);
+
+    loopback.send_file(path.clone(), loopback.progress()).expect("Failed to send file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("hello.bin")).unwrap(), content);
+    assert_eq!(*loopback.confirmed_codes.lock().unwrap(), vec![loopback.request(0).get_verification_code().unwrap()]);
+    assert_eq!(loopback.receive_events.lock().unwrap()[0], format!("code {}", loopback.confirmed_codes.lock().unwrap()[0]));
+
+    // Both devices are paired now, so there is no code to compare
+    fs::remove_file(loopback.inbox.join("hello.bin")).unwrap();
+    loopback.send_file(path, loopback.progress()).expect("Failed to send file again");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("hello.bin")).unwrap(), content);
+    assert_eq!(loopback.confirmed_codes.lock().unwrap().len(), 1);
+    assert_eq!(loopback.request(1).get_verification_code(), None);
+    assert_eq!(loopback.transfer_events().iter().filter(|event| *event == "finished").count(), 2);
+    assert_eq!(*loopback.send_events.lock().unwrap(), vec!["finished", "finished"]);
+}
+
+#[test]
+pub fn receiver_shows_code_before_sender_confirms() {
+    let loopback = Loopback::new("early-code", accept());
+    let (path, _) = loopback.write_outbox("a.bin", 100, 1);
+    let (verification_codes, received_codes) = channel();
+
+    let send = loopback.send_file_in_background(path, loopback.progress_with_codes(verification_codes));
+    let (verification_code, send_id) = received_codes.recv().expect("No verification code");
+    thread::sleep(Duration::from_millis(300));
+
+    // Nothing about the transfer has arrived, but the receiver already shows the same code
+    assert!(loopback.requests.lock().unwrap().is_empty());
+    assert_eq!(*loopback.receive_events.lock().unwrap(), vec![format!("code {}", verification_code)]);
+
+    loopback.sender.confirm_verification_code(send_id, false);
+
+    let result = send.join().unwrap();
+    assert!(matches!(result, Err(ConnectErrors::VerificationRejected)), "{:?}", result);
+
+    loopback.settle();
+    assert_eq!(loopback.last_receive_event().as_deref(), Some("aborted"));
+    assert!(loopback.requests.lock().unwrap().is_empty());
+}
+
+#[test]
+pub fn sends_batch_of_files() {
+    let loopback = Loopback::new("batch", accept());
+    let (a, a_content) = loopback.write_outbox("a.bin", 5_000, 1);
+    let (empty, _) = loopback.write_outbox("empty.bin", 0, 0);
+    let (c, c_content) = loopback.write_outbox("c.bin", 70_000, 7);
+
+    futures::executor::block_on(loopback.sender.send_files(loopback.receiver_device.clone(), vec![a, empty, c], loopback.progress()))
+        .expect("Failed to send files");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("a.bin")).unwrap(), a_content);
+    assert_eq!(fs::read(loopback.inbox.join("empty.bin")).unwrap(), Vec::<u8>::new());
+    assert_eq!(fs::read(loopback.inbox.join("c.bin")).unwrap(), c_content);
+
+    let file_names: Vec<String> = loopback.request(0).get_file_manifest().into_iter().map(|entry| entry.file_name).collect();
+    assert_eq!(file_names, vec!["a.bin", "empty.bin", "c.bin"]);
+    assert_eq!(loopback.transfer_events(), vec!["handshake", "file 0 done", "file 1 done", "file 2 done", "finished"]);
+}
+
+#[test]
+pub fn sends_directory() {
+    let loopback = Loopback::new("directory", accept());
+    let (_, a_content) = loopback.write_outbox("Photos/a.jpg", 3_000, 1);
+    let (_, b_content) = loopback.write_outbox("Photos/nested/b.jpg", 9_000, 2);
+    fs::create_dir_all(loopback.outbox.join("Photos/empty")).unwrap();
+
+    let directory = loopback.outbox.join("Photos").to_string_lossy().to_string();
+    futures::executor::block_on(loopback.sender.send_directory(loopback.receiver_device.clone(), directory, loopback.progress()))
+        .expect("Failed to send directory");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("Photos/a.jpg")).unwrap(), a_content);
+    assert_eq!(fs::read(loopback.inbox.join("Photos/nested/b.jpg")).unwrap(), b_content);
+    assert!(loopback.inbox.join("Photos/empty").is_dir());
+
+    let intent = loopback.request(0).get_file_transfer_intent().unwrap();
+    assert_eq!(intent.file_name.as_deref(), Some("Photos"));
+    assert_eq!(intent.file_count, 2);
+    assert_eq!(intent.file_size, 12_000);
+}
+
+#[test]
+pub fn keeps_part_files_until_transfer_is_complete() {
+    let loopback = Loopback::new("part-files", accept());
+    let (path, content) = loopback.write_outbox("huge.bin", 30_000_000, 4);
+
+    let send = loopback.send_file_in_background(path, loopback.progress());
+    loopback.wait_for_partial_content();
+    assert!(!loopback.inbox.join("huge.bin").exists());
+
+    send.join().unwrap().expect("Failed to send file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("huge.bin")).unwrap(), content);
+    assert_eq!(loopback.partial_size(), 0);
+    assert_eq!(fs::read_dir(&loopback.inbox).unwrap().count(), 1);
+}
+
+/// Leaves the part file and resume state of an interrupted transfer of `big.bin`, which was sent before.
+fn interrupt_transfer(loopback: &Loopback, partial_content: &[u8]) -> String {
+    let request = loopback.request(0);
+    let transfer_id = request.get_file_transfer_intent().unwrap().transfer_id;
+    let sender_identity_public_key = request.get_sender_identity_public_key();
+    let storage_id = storage_id(&transfer_id, &sender_identity_public_key).unwrap();
+    let file_storage = loopback.inbox.to_str().unwrap();
+
+    fs::remove_file(loopback.inbox.join("big.bin")).unwrap();
+    fs::write(partial_file_path(file_storage, &storage_id, 0), partial_content).unwrap();
+
+    save_state(file_storage, &ResumeState {
+        transfer_id,
+        sender_identity_public_key,
+        files: request.get_file_manifest(),
+        offset: partial_content.len() as u64,
+        local_file_names: vec!["big.bin".to_string()],
+        ..Default::default()
+    }).expect("Failed to save resume state");
+
+    return storage_id;
+}
+
+#[test]
+pub fn resumes_interrupted_transfer() {
+    let loopback = Loopback::new("resume", accept());
+    let (path, content) = loopback.write_outbox("big.bin", 300_000, 3);
+
+    loopback.send_file(path.clone(), loopback.progress()).expect("Failed to send file");
+    loopback.settle();
+
+    let storage_id = interrupt_transfer(&loopback, &content[..100_000]);
+
+    loopback.send_file(path, loopback.progress()).expect("Failed to resume file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("big.bin")).unwrap(), content);
+
+    let first_progress = loopback.first_progress.lock().unwrap().clone();
+    assert_eq!(first_progress[0], 0.0);
+    assert!((first_progress[1] - 1.0 / 3.0).abs() < 1e-9, "{:?}", first_progress);
+
+    assert!(!loopback.inbox.join(format!(".{}.resume", storage_id)).exists());
+    assert_eq!(fs::read_dir(&loopback.inbox).unwrap().count(), 1);
+}
+
+#[test]
+pub fn corrupted_content_fails_integrity_check() {
+    let loopback = Loopback::new("integrity", accept());
+    let (path, _) = loopback.write_outbox("big.bin", 300_000, 3);
+
+    loopback.send_file(path.clone(), loopback.progress()).expect("Failed to send file");
+    loopback.settle();
+
+    // The part file on the receiver does not match what the sender skips
+    interrupt_transfer(&loopback, &vec![0u8; 100_000]);
+
+    let result = loopback.send_file(path, loopback.progress());
+    assert!(matches!(result, Err(ConnectErrors::IntegrityCheckFailed)), "{:?}", result);
+
+    loopback.settle();
+    assert!(!loopback.inbox.join("big.bin").exists());
+    assert_eq!(loopback.partial_size(), 0);
+}
+
+#[test]
+pub fn refuses_duplicate_active_transfer() {
+    let loopback = Loopback::new("duplicate", accept());
+    let (path, content) = loopback.write_outbox("huge.bin", 30_000_000, 5);
+
+    let first_send = loopback.send_file_in_background(path.clone(), loopback.progress());
+    loopback.wait_for_partial_content();
+
+    let result = loopback.send_file(path, loopback.progress());
+    assert!(matches!(result, Err(ConnectErrors::ReceiverBusy)), "{:?}", result);
+
+    first_send.join().unwrap().expect("Failed to send file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("huge.bin")).unwrap(), content);
+    assert!(loopback.receive_events.lock().unwrap().contains(&"declined ReceiverBusy".to_string()));
+}
+
+#[test]
+pub fn sends_clipboard() {
+    let loopback = Loopback::new("clipboard", accept());
+    let image = ClipboardItem {
+        mime_type: "image/png".to_string(),
+        data: (0..200_000u32).map(|index| index as u8).collect()
+    };
+    let html = ClipboardItem {
+        mime_type: "text/html".to_string(),
+        data: b"<b>hi</b>".to_vec()
+    };
+
+    futures::executor::block_on(loopback.sender.send_clipboard(loopback.receiver_device.clone(), vec![ClipboardItem::text("hello clipboard"), html, image], loopback.progress()))
+        .expect("Failed to send clipboard");
+    loopback.settle();
+
+    assert!(matches!(loopback.request(0).get_intent_type(), ConnectionIntentType::Clipboard));
+    assert_eq!(loopback.transfer_events(), vec![
+        "handshake",
+        "file 0 done",
+        "file 1 done",
+        "file 2 done",
+        "clipboard text/plain=15,text/html=9,image/png=200000",
+        "finished"
+    ]);
+    assert_eq!(loopback.last_send_event().as_deref(), Some("finished"));
+
+    // Nothing is written to the file storage
+    assert_eq!(fs::read_dir(&loopback.inbox).unwrap().count(), 0);
+}
+
+#[test]
+pub fn refuses_invalid_clipboard() {
+    let loopback = Loopback::new("invalid-clipboard", accept());
+    let item = ClipboardItem {
+        mime_type: "nonsense".to_string(),
+        data: vec![1]
+    };
+
+    let result = futures::executor::block_on(loopback.sender.send_clipboard(loopback.receiver_device.clone(), vec![item], loopback.progress()));
+    assert!(matches!(result, Err(ConnectErrors::InvalidClipboardContent { .. })), "{:?}", result);
+}
+
+#[test]
+pub fn declines_with_reason() {
+    let loopback = Loopback::new("decline", Arc::new(|request: Arc<ConnectionRequest>| {
+        request.decline_with_reason(DeclineReason::ReceiverBusy, Some("later".to_string()));
+    }));
+    let (path, _) = loopback.write_outbox("a.bin", 100, 1);
+
+    let result = loopback.send_file(path, loopback.progress());
+    assert!(matches!(result, Err(ConnectErrors::ReceiverBusy)), "{:?}", result);
+    assert_eq!(loopback.last_send_event().as_deref(), Some("declined ReceiverBusy Some(\"later\")"));
+    assert!(!loopback.inbox.join("a.bin").exists());
+    assert!(loopback.receiver.get_trusted_devices().is_empty());
+}
+
+#[test]
+pub fn declines_pending_requests() {
+    let loopback = Loopback::new("pending", ignore());
+    let (path, _) = loopback.write_outbox("a.bin", 1000, 1);
+
+    let send = loopback.send_file_in_background(path, loopback.progress());
+    assert!(wait_for(&loopback.requests, |_| true, Duration::from_secs(5)), "No request arrived");
+    assert_eq!(loopback.receiver.get_pending_requests().len(), 1);
+
+    loopback.receiver.decline_pending_requests();
+
+    assert!(matches!(send.join().unwrap(), Err(ConnectErrors::Declined)));
+    assert!(loopback.receiver.get_pending_requests().is_empty());
+}
+
+#[test]
+pub fn unanswered_request_expires() {
+    let loopback = Loopback::new("expiry", ignore());
+    loopback.receiver.set_request_timeout(1);
+    let (path, _) = loopback.write_outbox("a.bin", 1000, 1);
+
+    let result = loopback.send_file(path, loopback.progress());
+    assert!(matches!(result, Err(ConnectErrors::RequestExpired)), "{:?}", result);
+
+    loopback.settle();
+    assert_eq!(loopback.last_receive_event().as_deref(), Some("expired"));
+    assert_eq!(loopback.last_send_event().as_deref(), Some("expired"));
+    assert!(loopback.receiver.get_pending_requests().is_empty());
+
+    // Answering too late changes nothing
+    loopback.request(0).accept();
+    assert!(!loopback.inbox.join("a.bin").exists());
+}
+
+#[test]
+pub fn refuses_transfers_above_max_size() {
+    let loopback = Loopback::new("max-size", accept());
+    loopback.receiver.set_max_transfer_size(1000);
+    let (large, _) = loopback.write_outbox("a.bin", 5000, 1);
+
+    let result = loopback.send_file(large, loopback.progress());
+    assert!(matches!(result, Err(ConnectErrors::TransferTooLarge)), "{:?}", result);
+    assert!(loopback.requests.lock().unwrap().is_empty());
+
+    loopback.settle();
+    assert_eq!(loopback.last_receive_event().as_deref(), Some("declined TransferTooLarge"));
+
+    let (small, content) = loopback.write_outbox("b.bin", 500, 1);
+    loopback.send_file(small, loopback.progress()).expect("Failed to send small file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("b.bin")).unwrap(), content);
+}
+
+#[test]
+pub fn renames_colliding_file() {
+    let loopback = Loopback::new("collision", accept());
+    fs::write(loopback.inbox.join("a.txt"), b"existing").unwrap();
+    let (path, content) = loopback.write_outbox("a.txt", 5000, 3);
+
+    loopback.send_file(path, loopback.progress()).expect("Failed to send file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("a.txt")).unwrap(), b"existing");
+    assert_eq!(fs::read(loopback.inbox.join("a (1).txt")).unwrap(), content);
+}
+
+#[test]
+pub fn failing_collision_keeps_existing_file() {
+    let loopback = Loopback::new("collision-fail", Arc::new(|request: Arc<ConnectionRequest>| {
+        request.set_file_collision_strategy(FileCollisionStrategy::Fail);
+        request.accept();
+    }));
+    fs::write(loopback.inbox.join("a.txt"), b"existing").unwrap();
+    let (path, _) = loopback.write_outbox("a.txt", 5000, 3);
+
+    let result = loopback.send_file(path, loopback.progress());
+    assert!(matches!(result, Err(ConnectErrors::FileAlreadyExists)), "{:?}", result);
+
+    loopback.settle();
+    assert_eq!(fs::read(loopback.inbox.join("a.txt")).unwrap(), b"existing");
+    assert_eq!(loopback.last_receive_event().as_deref(), Some("cancelled"));
+    assert!(loopback.receiver.get_trusted_devices().is_empty());
+}
+
+#[test]
+pub fn sender_cancels_transfer() {
+    let loopback = Loopback::new("sender-cancel", accept());
+    let (path, _) = loopback.write_outbox("huge.bin", 60_000_000, 5);
+
+    let send = loopback.send_file_in_background(path, loopback.progress());
+    let send_id = loopback.wait_for_send_id();
+    loopback.wait_for_partial_content();
+
+    loopback.sender.cancel_send(send_id);
+    send.join().unwrap().expect("Cancelling is not an error");
+    loopback.settle();
+
+    assert_eq!(loopback.last_receive_event().as_deref(), Some("cancelled"));
+    assert_eq!(loopback.last_send_event().as_deref(), Some("cancelled"));
+    assert!(!loopback.inbox.join("huge.bin").exists());
+    assert_eq!(loopback.partial_size(), 0);
+}
+
+#[test]
+pub fn receiver_cancels_transfer() {
+    let loopback = Loopback::new("receiver-cancel", accept());
+    let (path, _) = loopback.write_outbox("huge.bin", 60_000_000, 9);
+
+    let send = loopback.send_file_in_background(path, loopback.progress());
+    loopback.wait_for_partial_content();
+
+    futures::executor::block_on(loopback.request(0).cancel());
+    send.join().unwrap().expect("Cancelling is not an error");
+    loopback.settle();
+
+    assert_eq!(loopback.last_receive_event().as_deref(), Some("cancelled"));
+    assert_eq!(loopback.last_send_event().as_deref(), Some("cancelled by receiver"));
+    assert!(!loopback.inbox.join("huge.bin").exists());
+}
+
+#[test]
+pub fn cancelled_directory_is_removed() {
+    let loopback = Loopback::new("directory-cancel", accept());
+    loopback.write_outbox("Big/nested/huge.bin", 60_000_000, 5);
+    fs::create_dir_all(loopback.outbox.join("Big/empty")).unwrap();
+
+    let sender = loopback.sender.clone();
+    let receiver_device = loopback.receiver_device.clone();
+    let directory = loopback.outbox.join("Big").to_string_lossy().to_string();
+    let progress = loopback.progress();
+    let send = thread::spawn(move || futures::executor::block_on(sender.send_directory(receiver_device, directory, progress)));
+
+    let send_id = loopback.wait_for_send_id();
+    loopback.wait_for_partial_content();
+    assert!(loopback.inbox.join("Big/empty").is_dir());
+
+    loopback.sender.cancel_send(send_id);
+    send.join().unwrap().expect("Cancelling is not an error");
+    loopback.settle();
+
+    assert!(!loopback.inbox.join("Big").exists());
+}
+
+#[test]
+pub fn cancel_ends_wait_for_answer() {
+    let loopback = Loopback::new("cancel-answer", ignore());
+    let (path, _) = loopback.write_outbox("a.bin", 100, 1);
+
+    let send = loopback.send_file_in_background(path, loopback.progress());
+    let send_id = loopback.wait_for_send_id();
+    assert!(wait_for(&loopback.requests, |_| true, Duration::from_secs(5)), "No request arrived");
+
+    let start = Instant::now();
+    loopback.sender.cancel_send(send_id);
+
+    let result = send.join().unwrap();
+    assert!(matches!(result, Err(ConnectErrors::Cancelled)), "{:?}", result);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+pub fn cancel_ends_verification() {
+    let loopback = Loopback::new("cancel-verification", accept());
+    let (path, _) = loopback.write_outbox("a.bin", 100, 1);
+    let (verification_codes, _received_codes) = channel::<(String, String)>();
+
+    let send = loopback.send_file_in_background(path, loopback.progress_with_codes(verification_codes));
+    let send_id = loopback.wait_for_send_id();
+    thread::sleep(Duration::from_millis(300));
+
+    loopback.sender.cancel_send(send_id);
+
+    let result = send.join().unwrap();
+    assert!(matches!(result, Err(ConnectErrors::Cancelled)), "{:?}", result);
+    assert!(loopback.requests.lock().unwrap().is_empty());
+}
+
+#[test]
+pub fn unconfirmed_verification_is_rejected() {
+    let loopback = Loopback::new("verification-timeout", accept());
+    loopback.sender.set_request_timeout(1);
+    let (path, _) = loopback.write_outbox("a.bin", 100, 1);
+    let (verification_codes, _received_codes) = channel::<(String, String)>();
+
+    let start = Instant::now();
+    let result = loopback.send_file(path, loopback.progress_with_codes(verification_codes));
+
+    assert!(matches!(result, Err(ConnectErrors::VerificationRejected)), "{:?}", result);
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+pub fn idle_connection_does_not_block_others() {
+    let loopback = Loopback::new("idle", accept());
+    let _idle_connection = TcpStream::connect(("127.0.0.1", loopback.receiver_port())).expect("Failed to connect");
+    thread::sleep(Duration::from_millis(200));
+
+    let (path, content) = loopback.write_outbox("a.bin", 1000, 1);
+    loopback.send_file(path, loopback.progress()).expect("Failed to send file");
+    loopback.settle();
+
+    assert_eq!(fs::read(loopback.inbox.join("a.bin")).unwrap(), content);
+}
+
+#[test]
+pub fn idle_connection_is_closed_after_request_timeout() {
+    let loopback = Loopback::new("idle-close", accept());
+    loopback.receiver.set_request_timeout(1);
+
+    let mut idle_connection = TcpStream::connect(("127.0.0.1", loopback.receiver_port())).expect("Failed to connect");
+    idle_connection.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let start = Instant::now();
+    let mut buffer = [0u8; 1024];
+
+    // The receiver sends its header right away, and nothing else until it gives up
+    while let Ok(read_size) = idle_connection.read(&mut buffer) {
+        if read_size == 0 {
+            break;
+        }
+    }
+
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
@@ -0,0 +1,46 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use data_rct::protocol::discovery::{Device, DeviceConnectionInfo, DeviceDiscoveryMessage, TcpConnectionInfo};
+use data_rct::protocol::discovery::device_discovery_message::Content;
+use data_rct::protocol::prost::Message;
+use data_rct::udp::{UdpAnnouncer, UdpBrowser};
+use crate::helper::wait_for;
+
+mod helper;
+
+const DEVICE_ID: &str = "0C5B6D4A-3E51-4F0B-9C7B-5D0E7A1F2B93";
+
+#[test]
+pub fn announcements_and_goodbyes_are_received() {
+    let messages = Arc::new(Mutex::new(vec![]));
+    let received_messages = messages.clone();
+
+    // Both sockets share the port, like two processes on one host
+    let _browser = UdpBrowser::start(move |data| {
+        if let Ok(DeviceDiscoveryMessage { content: Some(content) }) = DeviceDiscoveryMessage::decode_length_delimited(data.as_slice()) {
+            received_messages.lock().unwrap().push(content);
+        }
+    }).expect("Failed to start UDP browser");
+
+    let device_connection_info = DeviceConnectionInfo {
+        device: Some(Device {
+            id: DEVICE_ID.to_string(),
+            name: "UDP Device".to_string(),
+            device_type: 0
+        }),
+        tcp: Some(TcpConnectionInfo {
+            hostname: "127.0.0.1".to_string(),
+            port: 4242
+        }),
+        ble: None,
+        identity_public_key: vec![]
+    };
+
+    let announcer = UdpAnnouncer::start(&device_connection_info).expect("Failed to start UDP announcer");
+
+    assert!(wait_for(&messages, |content| *content == Content::DeviceConnectionInfo(device_connection_info.clone()), Duration::from_secs(5)));
+
+    drop(announcer);
+
+    assert!(wait_for(&messages, |content| *content == Content::OfflineDeviceId(DEVICE_ID.to_string()), Duration::from_secs(5)));
+}
@@ -1,15 +1,21 @@
 use std::io;
 use std::sync::Arc;
 
-pub use data_rct::{BLE_CHARACTERISTIC_UUID, BLE_SERVICE_UUID, ClipboardTransferIntent};
+pub use data_rct::{BLE_CHARACTERISTIC_UUID, BLE_SERVICE_UUID, ClipboardRepresentation, ClipboardTransferIntent, DeclineReason};
+pub use data_rct::clipboard::ClipboardItem;
 pub use data_rct::connection_request::{ConnectionRequest, ReceiveProgressState, ReceiveProgressDelegate};
 pub use data_rct::Device;
-pub use data_rct::discovery::{BleDiscoveryImplementationDelegate, Discovery};
+pub use data_rct::device_registry::{DeviceRegistry, DiscoveredDevice};
+pub use data_rct::discovery::{BleDiscoveryImplementationDelegate, Discovery, DiscoveryMethod};
 pub use data_rct::DiscoveryDelegate as DeviceListUpdateDelegate;
 pub use data_rct::encryption::EncryptedStream;
+pub use data_rct::file_names::FileCollisionStrategy;
 pub use data_rct::nearby::{ConnectionMedium, SendProgressState, SendProgressDelegate, BleServerImplementationDelegate, L2CapDelegate, NearbyConnectionDelegate, NearbyServer};
 pub use data_rct::nearby::ConnectionIntentType;
-pub use data_rct::protocol::communication::FileTransferIntent;
+pub use data_rct::protocol::communication::{FileManifestEntry, FileTransferIntent};
+pub use data_rct::protocol::pairing::TrustedDevice;
+pub use data_rct::pairing::TrustState;
+pub use data_rct::policy::{PolicyDecision, PolicyRule, TimeWindow};
 use data_rct::protocol::discovery::{BluetoothLeConnectionInfo, TcpConnectionInfo};
 pub use data_rct::stream::NativeStreamDelegate;
 pub use data_rct::transmission::TransmissionSetupError;
@@ -57,12 +63,24 @@ impl InternalDiscovery {
         return self.handler.read().expect("Failed to lock handler").get_devices()
     }
 
+    pub fn get_discovered_devices(&self) -> Vec<DiscoveredDevice> {
+        return self.handler.read().expect("Failed to lock handler").get_discovered_devices()
+    }
+
+    pub fn get_device_registry(&self) -> Arc<DeviceRegistry> {
+        return self.handler.read().expect("Failed to lock handler").get_device_registry()
+    }
+
+    pub fn set_device_ttl(&self, seconds: u64) {
+        self.handler.read().expect("Failed to lock handler").set_device_ttl(seconds);
+    }
+
     pub fn add_ble_implementation(&self, implementation: Box<dyn BleDiscoveryImplementationDelegate>) {
         self.handler.write().expect("Failed to lock handler").add_ble_implementation(implementation);
     }
 
-    pub fn start(&self) {
-        self.handler.read().expect("Failed to lock handler").start();
+    pub fn start(&self, methods: Vec<DiscoveryMethod>) {
+        self.handler.read().expect("Failed to lock handler").start(methods);
     }
 
     pub fn stop(&self) {
@@ -2,6 +2,11 @@ use std::sync::Arc;
 
 pub use data_rct::{nearby::{BleServerImplementationDelegate, L2CapDelegate, NearbyConnectionDelegate, NearbyServer, SendProgressDelegate}, Device};
 use data_rct::protocol::discovery::{BluetoothLeConnectionInfo, DeviceDiscoveryMessage, TcpConnectionInfo};
+use data_rct::clipboard::ClipboardItem;
+use data_rct::connection_request::ConnectionRequest;
+use data_rct::device_registry::DeviceRegistry;
+use data_rct::policy::PolicyRule;
+use data_rct::protocol::pairing::TrustedDevice;
 use data_rct::protocol::discovery::device_discovery_message::Content;
 use data_rct::protocol::prost::Message;
 pub use data_rct::stream::NativeStreamDelegate;
@@ -19,8 +24,8 @@ pub struct InternalNearbyServer {
 }
 
 impl InternalNearbyServer {
-    pub fn new(my_device: Device, file_storage: String, delegate: Option<Box<dyn NearbyConnectionDelegate>>) -> Self {
-        let server = NearbyServer::new(my_device, file_storage, delegate);
+    pub fn new(my_device: Device, file_storage: String, config_storage: String, delegate: Option<Box<dyn NearbyConnectionDelegate>>) -> Self {
+        let server = NearbyServer::new(my_device, file_storage, config_storage, delegate);
 
         let async_runtime = tokio::runtime::Builder::new_current_thread()
             .build()
@@ -39,6 +44,26 @@ impl InternalNearbyServer {
         return self.handler.get_current_ip();
     }
 
+    pub fn get_identity_fingerprint(&self) -> String {
+        return self.handler.get_identity_fingerprint();
+    }
+
+    pub fn get_trusted_devices(&self) -> Vec<TrustedDevice> {
+        return self.handler.get_trusted_devices();
+    }
+
+    pub fn pair_device(&self, device: Device, identity_public_key: Vec<u8>) -> Result<(), PairingErrors> {
+        return self.handler.pair_device(device, identity_public_key);
+    }
+
+    pub fn forget_device(&self, device_id: String) -> Result<(), PairingErrors> {
+        return self.handler.forget_device(device_id);
+    }
+
+    pub fn confirm_verification_code(&self, send_id: String, confirmed: bool) {
+        self.handler.confirm_verification_code(send_id, confirmed);
+    }
+
     pub fn add_l2_cap_client(&self, delegate: Box<dyn L2CapDelegate>) {
         self.handler.add_l2_cap_client(delegate);
     }
@@ -99,6 +124,46 @@ impl InternalNearbyServer {
         return self.async_runtime.block_on(self.handler.send_file(receiver, file_path, progress_delegate));
     }
 
+    pub fn send_files(&self, receiver: Device, file_paths: Vec<String>, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        return self.async_runtime.block_on(self.handler.send_files(receiver, file_paths, progress_delegate));
+    }
+
+    pub fn send_directory(&self, receiver: Device, directory_path: String, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        return self.async_runtime.block_on(self.handler.send_directory(receiver, directory_path, progress_delegate));
+    }
+
+    pub fn send_clipboard(&self, receiver: Device, items: Vec<ClipboardItem>, progress_delegate: Option<Box<dyn SendProgressDelegate>>) -> Result<(), ConnectErrors> {
+        return self.async_runtime.block_on(self.handler.send_clipboard(receiver, items, progress_delegate));
+    }
+
+    pub fn cancel_send(&self, send_id: String) {
+        self.handler.cancel_send(send_id);
+    }
+
+    pub fn get_pending_requests(&self) -> Vec<Arc<ConnectionRequest>> {
+        return self.handler.get_pending_requests();
+    }
+
+    pub fn decline_pending_requests(&self) {
+        self.handler.decline_pending_requests();
+    }
+
+    pub fn set_request_timeout(&self, seconds: u64) {
+        self.handler.set_request_timeout(seconds);
+    }
+
+    pub fn set_max_transfer_size(&self, bytes: u64) {
+        self.handler.set_max_transfer_size(bytes);
+    }
+
+    pub fn set_auto_accept_rules(&self, rules: Vec<PolicyRule>) {
+        self.handler.set_auto_accept_rules(rules);
+    }
+
+    pub fn set_device_registry(&self, device_registry: Arc<DeviceRegistry>) {
+        self.handler.set_device_registry(device_registry);
+    }
+
     pub fn stop(&self) {
         self.handler.stop();
     }
@@ -3,6 +3,8 @@ use std::io::Result;
 fn main() -> Result<()> {
     prost_build::compile_protos(&["src/communication.proto"], &["src/"])?;
     prost_build::compile_protos(&["src/discovery.proto"], &["src/"])?;
+    prost_build::compile_protos(&["src/pairing.proto"], &["src/"])?;
+    prost_build::compile_protos(&["src/resume.proto"], &["src/"])?;
 
     return Ok(());
 }
@@ -9,6 +9,14 @@ pub mod communication {
     include!(concat!(env!("OUT_DIR"), "/data_rct.communication.rs"));
 }
 
+pub mod pairing {
+    include!(concat!(env!("OUT_DIR"), "/data_rct.pairing.rs"));
+}
+
+pub mod resume {
+    include!(concat!(env!("OUT_DIR"), "/data_rct.resume.rs"));
+}
+
 pub trait DiscoveryDelegate: Send + Sync + Debug {
     fn device_added(&self, value: discovery::Device);
     fn device_removed(&self, device_id: String);